@@ -1,5 +1,259 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::time::{Duration, Instant};
+use tokio::runtime;
+use webserver_from_scratch::{
+    http_client::{Connection, Url},
+    http_request::{Method, Request},
+};
+
+const USAGE: &str = "usage: client bench [options] <url>
+
+options:
+  -c, --connections <n>  number of open connections (default: 50)
+  -t, --threads <n>      number of worker threads (default: number of cpus)
+  -d, --duration <secs>  duration of the benchmark in seconds (default: 10)
+  -p, --pipeline <n>     number of pipelined requests per connection (default: 1)
+  --no-keepalive         open a new connection for every request";
+
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct BenchOptions {
+    url: Url,
+    connections: usize,
+    threads: usize,
+    duration: Duration,
+    pipeline: usize,
+    keep_alive: bool,
+}
+
+#[derive(Default)]
+struct BenchStats {
+    // latencies in microseconds
+    latencies: Vec<u64>,
+    non_success: usize,
+    errors: usize,
+}
 
 fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(|arg| arg.as_str()) {
+        Some("bench") => {
+            let options = parse_bench_options(&args[1..])?;
+            bench(options)
+        }
+        _ => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    }
+}
+
+fn parse_bench_options(args: &[String]) -> Result<BenchOptions> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut options = BenchOptions {
+        url: Url::parse("http://localhost:8080/")?,
+        connections: 50,
+        threads,
+        duration: Duration::from_secs(10),
+        pipeline: 1,
+        keep_alive: true,
+    };
+
+    let mut url = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || -> Result<usize> {
+            args.next()
+                .and_then(|value| value.parse().ok())
+                .filter(|value| *value > 0)
+                .ok_or_else(|| anyhow!("`{}` expects a positive number\n\n{}", arg, USAGE))
+        };
+
+        match arg.as_str() {
+            "-c" | "--connections" => options.connections = value()?,
+            "-t" | "--threads" => options.threads = value()?,
+            "-d" | "--duration" => options.duration = Duration::from_secs(value()? as u64),
+            "-p" | "--pipeline" => options.pipeline = value()?,
+            "--no-keepalive" => options.keep_alive = false,
+            arg if !arg.starts_with('-') && url.is_none() => url = Some(Url::parse(arg)?),
+            arg => return Err(anyhow!("unknown argument `{}`\n\n{}", arg, USAGE)),
+        }
+    }
+
+    if let Some(url) = url {
+        options.url = url;
+    }
+
+    // pipelining only makes sense if the connection is kept open
+    if !options.keep_alive {
+        options.pipeline = 1;
+    }
+
+    Ok(options)
+}
+
+fn bench(options: BenchOptions) -> Result<()> {
+    let rt = runtime::Builder::new_multi_thread()
+        .worker_threads(options.threads)
+        .enable_all()
+        .build()?;
+
+    let keep_alive = if options.keep_alive {
+        ""
+    } else {
+        ", no keep-alive"
+    };
+    println!(
+        "Running {}s test @ http://{}{}\n  {} threads and {} connections, pipeline depth {}{}",
+        options.duration.as_secs(),
+        options.url.authority(),
+        options.url.path,
+        options.threads,
+        options.connections,
+        options.pipeline,
+        keep_alive
+    );
+
+    let mut request = Request::new();
+    request.method = Some(Method::GET);
+    request.path = Some(options.url.path.clone());
+    request.set_header("Host", &options.url.authority());
+    if !options.keep_alive {
+        request.set_header("Connection", "close");
+    }
+
+    // all pipelined requests are written at once
+    let requests = request.build().repeat(options.pipeline);
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+
+    let stats = rt.block_on(async {
+        let workers: Vec<_> = (0..options.connections)
+            .map(|_| {
+                let url = options.url.clone();
+                let requests = requests.clone();
+                let (pipeline, keep_alive) = (options.pipeline, options.keep_alive);
+                tokio::spawn(run_connection(
+                    url, requests, pipeline, keep_alive, deadline,
+                ))
+            })
+            .collect();
+
+        let mut stats = BenchStats::default();
+        for worker in workers {
+            let worker_stats = worker.await?;
+            stats.latencies.extend(worker_stats.latencies);
+            stats.non_success += worker_stats.non_success;
+            stats.errors += worker_stats.errors;
+        }
+
+        Ok::<_, anyhow::Error>(stats)
+    })?;
+
+    print_stats(stats, start.elapsed());
     Ok(())
 }
+
+async fn run_connection(
+    url: Url,
+    requests: Vec<u8>,
+    pipeline: usize,
+    keep_alive: bool,
+    deadline: Instant,
+) -> BenchStats {
+    let mut stats = BenchStats::default();
+    let mut connection = None;
+
+    while Instant::now() < deadline {
+        let conn = match connection.as_mut() {
+            Some(conn) => conn,
+            None => match Connection::connect(&url.host, url.port).await {
+                Ok(conn) => connection.insert(conn),
+                Err(_) => {
+                    stats.errors += 1;
+                    // don't spin if the server is down
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+            },
+        };
+
+        let sent = Instant::now();
+        if conn.send_bytes(&requests).await.is_err() {
+            stats.errors += 1;
+            connection = None;
+            continue;
+        }
+
+        for _ in 0..pipeline {
+            let response = tokio::time::timeout(READ_TIMEOUT, conn.read_response(&Method::GET));
+            match response.await {
+                Ok(Ok((response, reusable))) => {
+                    stats.latencies.push(sent.elapsed().as_micros() as u64);
+                    if !(200..300).contains(&response.status_code.as_u16()) {
+                        stats.non_success += 1;
+                    }
+
+                    if !reusable || !keep_alive {
+                        connection = None;
+                        break;
+                    }
+                }
+                _ => {
+                    stats.errors += 1;
+                    connection = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    stats
+}
+
+fn print_stats(mut stats: BenchStats, elapsed: Duration) {
+    let requests = stats.latencies.len();
+    if requests == 0 {
+        println!("  no requests completed, socket errors: {}", stats.errors);
+        return;
+    }
+    stats.latencies.sort_unstable();
+
+    let micros = |us: u64| format!("{:.2}ms", us as f64 / 1000.0);
+    let percentile = |p: f64| -> u64 {
+        let index = ((requests as f64 * p / 100.0).ceil() as usize).clamp(1, requests);
+        stats.latencies[index - 1]
+    };
+
+    let mean = stats.latencies.iter().sum::<u64>() as f64 / requests as f64;
+    let variance = stats
+        .latencies
+        .iter()
+        .map(|us| (*us as f64 - mean).powi(2))
+        .sum::<f64>()
+        / requests as f64;
+
+    println!(
+        "  Latency     avg {}  stdev {:.2}ms  max {}",
+        micros(mean as u64),
+        variance.sqrt() / 1000.0,
+        micros(percentile(100.0))
+    );
+    println!("  Latency Distribution");
+    for p in [50.0, 75.0, 90.0, 99.0, 99.9] {
+        println!("    {:>5}%  {}", p, micros(percentile(p)));
+    }
+    println!("  {} requests in {:.2}s", requests, elapsed.as_secs_f64());
+    if stats.errors > 0 || stats.non_success > 0 {
+        println!(
+            "  Socket errors: {}, Non-2xx responses: {}",
+            stats.errors, stats.non_success
+        );
+    }
+    println!(
+        "Requests/sec: {:.2}",
+        requests as f64 / elapsed.as_secs_f64()
+    );
+}
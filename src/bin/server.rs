@@ -1,12 +1,30 @@
 #![feature(async_closure)]
 
 use anyhow::Result;
-use webserver_from_scratch::{middleware, router::Router, HTTPServer, LogLevel, StatusCode};
+use futures::{future, StreamExt, TryStreamExt};
+use std::{
+    env,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::time;
+use webserver_from_scratch::{
+    listener::ListenerBuilder,
+    middleware,
+    router::Router,
+    sse::Event,
+    tls::TlsConfig,
+    websocket::{self, DeflateConfig, Message, WebSocketConfig},
+    HTTPServer, LogLevel, RuntimeMode, StatusCode,
+};
 
 fn main() -> Result<()> {
     let mut server = HTTPServer::new();
     server.loglevel(LogLevel::Off);
 
+    // websockets and server-sent events need http/1.1, so HTTP/2 is opt-in
+    server.http2(env::var("HTTP2").is_ok());
+
     let hello_world_handler = middleware!(|ctx| {
         let resp = b"<h1>Hello World</h1>";
         ctx.response.content_type("text/html");
@@ -31,12 +49,52 @@ fn main() -> Result<()> {
         ctx.end();
     });
 
-    server.get(
-        "*",
-        middleware!(|ctx| {
-            webserver_from_scratch::websocket::accept_websocket(&mut ctx).await?;
-        }),
-    );
+    // echoes all messages back to the client
+    let websocket_handler = middleware!(|ctx| {
+        let config = WebSocketConfig {
+            deflate: Some(DeflateConfig::default()),
+            ..Default::default()
+        };
+        let websocket = websocket::upgrade_websocket(&mut ctx, config).await?;
+
+        tokio::spawn(async move {
+            let (reader, writer) = websocket.split();
+            let messages = reader.try_filter(|message| {
+                future::ready(matches!(message, Message::Text(_) | Message::Binary(_)))
+            });
+
+            if let Err(e) = messages.forward(writer).await {
+                println!("websocket error: {}", e);
+            }
+        });
+    });
+
+    server.get("/ws", websocket_handler);
+
+    // sends a counter every second, continuing where the client left off after reconnecting
+    let events_handler = middleware!(|ctx| {
+        let events = ctx.event_stream().await?;
+
+        tokio::spawn(async move {
+            let mut id: u64 = events
+                .last_event_id()
+                .and_then(|id| id.parse().ok())
+                .unwrap_or(0);
+
+            loop {
+                id += 1;
+                let mut event = Event::new();
+                event.id(&id.to_string()).data(&format!("tick {}", id));
+
+                if events.send(&event).await.is_err() {
+                    break;
+                }
+                time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+    });
+
+    server.get("/events", events_handler);
 
     server
         .get("/", hello_world_handler)
@@ -51,5 +109,56 @@ fn main() -> Result<()> {
         }),
     );
 
-    server.listen_blocking("[::1]:8080".parse().unwrap())
+    // e.g behind a reverse proxy on the same host
+    if let Ok(path) = env::var("UNIX_SOCKET") {
+        return server.listen_unix_blocking(path, Some(0o660));
+    }
+
+    // RUNTIME=current-thread or per-core, see BENCHMARK.md
+    match env::var("RUNTIME").as_deref() {
+        Ok("current-thread") => server.runtime(RuntimeMode::CurrentThread),
+        Ok("per-core") => server.runtime(RuntimeMode::ThreadPerCore),
+        _ => server.runtime(RuntimeMode::MultiThread),
+    };
+    if let Ok(threads) = env::var("WORKER_THREADS") {
+        server.worker_threads(threads.parse()?);
+    }
+    server.thread_name("http-worker");
+
+    // e.g MAX_CONNECTIONS=1000, with REJECT_WHEN_FULL set further clients get a 503
+    if let Ok(limit) = env::var("MAX_CONNECTIONS") {
+        server
+            .max_connections(limit.parse()?)
+            .reject_when_full(env::var("REJECT_WHEN_FULL").is_ok());
+    }
+
+    // a new server started with the same control socket takes over the listeners
+    if let Ok(path) = env::var("HOT_RESTART") {
+        server.hot_restart(path);
+    }
+
+    let mut listener = ListenerBuilder::new();
+    listener.nodelay(true);
+
+    // behind a load balancer which sends the PROXY protocol
+    listener.proxy_protocol(env::var("PROXY_PROTOCOL").is_ok());
+
+    // socket activated by systemd, the sockets are configured in the .socket unit
+    let activated = listener.systemd()? > 0;
+
+    // serve https if a certificate is configured
+    let port = match (env::var("TLS_CERT"), env::var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => {
+            listener.tls(TlsConfig::from_pem_files(cert, key)?);
+            8443
+        }
+        _ => 8080,
+    };
+
+    if !activated {
+        listener
+            .bind(SocketAddr::from((Ipv6Addr::LOCALHOST, port)))
+            .bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+    }
+    server.listen_with_blocking(&listener)
 }
@@ -0,0 +1,276 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    time,
+};
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+// how many connections are answered with a 503 at once, further ones are closed right away
+const MAX_PENDING_REJECTS: usize = 64;
+
+/// Counts the connections which are served right now, so they can be drained on shutdown,
+/// and limits how many are served at once
+#[derive(Default)]
+pub(crate) struct Connections {
+    active: AtomicUsize,
+    idle: Notify,
+    limit: Option<Arc<Semaphore>>,
+    rejects: Option<Arc<Semaphore>>,
+    closing: AtomicBool,
+    close: Notify,
+}
+
+impl Connections {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Connections::default())
+    }
+
+    pub(crate) fn with_limit(limit: usize) -> Arc<Self> {
+        assert!(limit > 0, "at least one connection has to be allowed");
+        Arc::new(Connections {
+            limit: Some(Arc::new(Semaphore::new(limit))),
+            rejects: Some(Arc::new(Semaphore::new(MAX_PENDING_REJECTS))),
+            ..Default::default()
+        })
+    }
+
+    /// Waits until a connection can be served, it is counted until the returned guard is dropped
+    pub(crate) async fn reserve(self: &Arc<Self>) -> ConnectionGuard {
+        let permit = match &self.limit {
+            // the semaphore is never closed
+            Some(limit) => limit.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        self.start(permit)
+    }
+
+    /// Like `reserve`, but `None` if the limit is reached
+    pub(crate) fn try_reserve(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        let permit = match &self.limit {
+            Some(limit) => Some(limit.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(self.start(permit))
+    }
+
+    /// Allows rejecting a connection with a response once the limit is reached,
+    /// `None` if too many are rejected right now
+    pub(crate) fn try_reject(&self) -> Option<OwnedSemaphorePermit> {
+        self.rejects.clone()?.try_acquire_owned().ok()
+    }
+
+    fn start(self: &Arc<Self>, permit: Option<OwnedSemaphorePermit>) -> ConnectionGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            connections: self.clone(),
+            _permit: permit,
+        }
+    }
+
+    pub(crate) fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Waits until all connections are closed, returns false if `timeout` passed first
+    pub(crate) async fn wait_idle(&self, timeout: Duration) -> bool {
+        let idle = async {
+            loop {
+                // registered before checking, so a notification in between isn't lost
+                let notified = self.idle.notified();
+                if self.active() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+
+        time::timeout(timeout, idle).await.is_ok()
+    }
+
+    /// Makes the connections stop, their tasks wait for `ConnectionGuard::closed`
+    pub(crate) fn close_all(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        self.close.notify_waiters();
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    connections: Arc<Connections>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionGuard {
+    /// Completes once the connection should be closed
+    pub(crate) async fn closed(&self) {
+        loop {
+            let notified = self.connections.close.notified();
+            if self.connections.closing.load(Ordering::SeqCst) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.connections.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.connections.idle.notify_waiters();
+        }
+    }
+}
+
+/// Slows down the accept loop while it fails, e.g because the process ran out of file descriptors
+#[derive(Default)]
+pub(crate) struct AcceptBackoff {
+    delay: Option<Duration>,
+}
+
+impl AcceptBackoff {
+    pub(crate) fn reset(&mut self) {
+        self.delay = None;
+    }
+
+    pub(crate) async fn error(&mut self, e: &io::Error) {
+        if let Some(delay) = self.next_delay(e) {
+            println!("couldn't get client, retrying in {:?}: {:?}", delay, e);
+            time::sleep(delay).await;
+        }
+    }
+
+    fn next_delay(&mut self, e: &io::Error) -> Option<Duration> {
+        // the client gave up before the connection was accepted, nothing to wait for
+        if matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionRefused
+        ) {
+            return None;
+        }
+
+        let delay = match self.delay {
+            Some(delay) => (delay * 2).min(MAX_ACCEPT_BACKOFF),
+            None => MIN_ACCEPT_BACKOFF,
+        };
+        self.delay = Some(delay);
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn wait_until_connections_are_closed() {
+        let connections = Connections::new();
+        assert!(connections.wait_idle(Duration::from_millis(10)).await);
+
+        let guard = connections.reserve().await;
+        assert_eq!(connections.active(), 1);
+        assert!(!connections.wait_idle(Duration::from_millis(10)).await);
+
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            drop(guard);
+        });
+        assert!(connections.wait_idle(Duration::from_secs(5)).await);
+        assert_eq!(connections.active(), 0);
+    }
+
+    #[test]
+    fn limit_pending_rejects() {
+        assert!(Connections::new().try_reject().is_none());
+
+        let connections = Connections::with_limit(1);
+        let mut rejects: Vec<_> = (0..MAX_PENDING_REJECTS)
+            .map(|_| connections.try_reject().unwrap())
+            .collect();
+        assert!(connections.try_reject().is_none());
+
+        rejects.pop();
+        assert!(connections.try_reject().is_some());
+    }
+
+    #[tokio::test]
+    async fn close_connections() {
+        let connections = Connections::new();
+        let connection = connections.reserve().await;
+        let closed = tokio::spawn(async move {
+            connection.closed().await;
+        });
+
+        let timeout = Duration::from_millis(20);
+        assert!(!connections.wait_idle(timeout).await);
+        connections.close_all();
+        closed.await.unwrap();
+        assert_eq!(connections.active(), 0);
+
+        // connections served afterwards are closed right away
+        connections.reserve().await.closed().await;
+    }
+
+    #[tokio::test]
+    async fn limit_concurrent_connections() {
+        let connections = Connections::with_limit(2);
+        let first = connections.reserve().await;
+        let _second = connections.try_reserve().unwrap();
+        assert!(connections.try_reserve().is_none());
+
+        let mut waiting = tokio::spawn({
+            let connections = connections.clone();
+            async move { connections.reserve().await }
+        });
+        let timeout = Duration::from_millis(20);
+        assert!(time::timeout(timeout, &mut waiting).await.is_err());
+
+        drop(first);
+        let _third = waiting.await.unwrap();
+        assert_eq!(connections.active(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one connection")]
+    fn reject_zero_limit() {
+        Connections::with_limit(0);
+    }
+
+    #[test]
+    fn back_off_on_accept_errors() {
+        let mut backoff = AcceptBackoff::default();
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert_eq!(backoff.next_delay(&aborted), None);
+
+        let too_many_files = io::Error::from_raw_os_error(24);
+        assert_eq!(
+            backoff.next_delay(&too_many_files),
+            Some(MIN_ACCEPT_BACKOFF)
+        );
+        assert_eq!(
+            backoff.next_delay(&too_many_files),
+            Some(MIN_ACCEPT_BACKOFF * 2)
+        );
+        for _ in 0..10 {
+            backoff.next_delay(&too_many_files);
+        }
+        assert_eq!(
+            backoff.next_delay(&too_many_files),
+            Some(MAX_ACCEPT_BACKOFF)
+        );
+
+        backoff.reset();
+        assert_eq!(
+            backoff.next_delay(&too_many_files),
+            Some(MIN_ACCEPT_BACKOFF)
+        );
+    }
+}
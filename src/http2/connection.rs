@@ -0,0 +1,1275 @@
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use super::{
+    frame::{
+        ErrorCode, Frame, Http2Error, Settings, DEFAULT_MAX_FRAME_SIZE, DEFAULT_WINDOW_SIZE,
+        MAX_WINDOW_SIZE, PREFACE,
+    },
+    hpack::{Decoder, Encoder, Header, HpackError},
+};
+use crate::{
+    http_request::{Method, Request},
+    http_response::ResponseBuilder,
+    StatusCode,
+};
+
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+const HEADER_TABLE_SIZE: usize = 4096;
+// limits for the compressed and the decoded headers of a request
+const MAX_HEADER_BLOCK_SIZE: usize = 30000;
+const MAX_HEADER_LIST_SIZE: usize = 30000;
+const MAX_BODY_SIZE: usize = 1 << 20;
+const COMMAND_QUEUE_SIZE: usize = 64;
+const READ_CHUNK_SIZE: usize = 16384;
+
+// headers which only make sense for http/1.1 connections
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Serves a connection on which the client sent the HTTP/2 preface, either because it
+/// knew the server speaks HTTP/2 (h2c prior knowledge) or because it was negotiated using ALPN.
+///
+/// `buffered` are bytes which were already read from the connection, `handler` is
+/// called for every request in a separate task.
+pub async fn serve_connection<S, F, Fut>(stream: S, buffered: &[u8], handler: F) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ResponseBuilder> + Send + 'static,
+{
+    Connection::new(stream, buffered, handler).run(None).await
+}
+
+/// Switches a http/1.1 connection to HTTP/2 after a request with `Upgrade: h2c`,
+/// the request is answered as the first HTTP/2 stream
+pub async fn serve_upgraded_connection<S, F, Fut>(
+    mut stream: S,
+    mut request: Request,
+    settings: Settings,
+    handler: F,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ResponseBuilder> + Send + 'static,
+{
+    stream
+        .write_all(
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n",
+        )
+        .await?;
+
+    // everything after the request body already belongs to the new protocol
+    let content_length = request
+        .headers
+        .get_str("Content-Length")
+        .ok()
+        .and_then(|length| length.trim().parse().ok())
+        .unwrap_or(0);
+    let buffered = request
+        .body
+        .split_off(content_length.min(request.body.len()));
+
+    for header in ["Connection", "Upgrade", "HTTP2-Settings"] {
+        request.headers.remove(header);
+    }
+
+    Connection::new(stream, &buffered, handler)
+        .run(Some((request, settings)))
+        .await
+}
+
+/// Checks if the request asks to switch to h2c and returns the settings it contains
+pub fn h2c_upgrade_settings(request: &Request) -> Option<Settings> {
+    if !request.headers.contains_token("Upgrade", "h2c")
+        || !request
+            .headers
+            .contains_token("Connection", "HTTP2-Settings")
+    {
+        return None;
+    }
+
+    let settings = request.headers.get_str("HTTP2-Settings").ok()?;
+    let settings = base64::decode_config(settings.trim(), base64::URL_SAFE_NO_PAD).ok()?;
+    Settings::parse(&settings).ok()
+}
+
+// messages to the task which writes to the connection
+enum Command {
+    Frame(Frame),
+    // settings of the client, which are acknowledged once applied
+    Settings(Settings),
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32,
+    },
+    // a stream was opened, responses and window updates for unknown streams are ignored
+    Open(u32),
+    // the stream was reset or abandoned before the response was sent
+    Close(u32),
+    Respond {
+        stream_id: u32,
+        headers: Vec<(String, String)>,
+        body: Bytes,
+    },
+    // a connection error, nothing is written afterwards
+    GoAway(ErrorCode),
+}
+
+struct Connection<S, F> {
+    stream: S,
+    buffered: BytesMut,
+    handler: Arc<F>,
+}
+
+impl<S, F, Fut> Connection<S, F>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ResponseBuilder> + Send + 'static,
+{
+    fn new(stream: S, buffered: &[u8], handler: F) -> Self {
+        Connection {
+            stream,
+            buffered: BytesMut::from(buffered),
+            handler: Arc::new(handler),
+        }
+    }
+
+    async fn run(self, upgrade: Option<(Request, Settings)>) -> Result<()> {
+        let (read, write) = io::split(self.stream);
+        let (commands, queue) = mpsc::channel(COMMAND_QUEUE_SIZE);
+
+        let mut writer = Writer::new(write);
+        if let Some((_, settings)) = upgrade.as_ref() {
+            writer.apply_settings(settings);
+        }
+        let writer = tokio::spawn(writer.run(queue));
+
+        let mut reader = Reader {
+            io: read,
+            buf: self.buffered,
+            decoder: Decoder::new(HEADER_TABLE_SIZE),
+            commands,
+            handler: self.handler,
+            streams: HashMap::new(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            last_stream_id: 0,
+            window: DEFAULT_WINDOW_SIZE as i64,
+            pending_headers: None,
+            settings_received: false,
+        };
+
+        if let Some((request, _)) = upgrade {
+            // the upgrade request is stream 1, which is already half closed
+            reader.last_stream_id = 1;
+            reader.send(Command::Open(1)).await;
+            reader.dispatch(1, request);
+        }
+
+        let result = reader.run().await;
+        if let Err(Http2Error::Connection(code, _)) = result {
+            reader.send(Command::GoAway(code)).await;
+        }
+
+        // the writer finishes once all responses are written
+        drop(reader);
+        writer.await??;
+        result.map_err(|e| e.into())
+    }
+}
+
+struct RecvStream {
+    request: Request,
+    window: i64,
+}
+
+struct PendingHeaders {
+    stream_id: u32,
+    block: BytesMut,
+    end_stream: bool,
+    dependency: Option<u32>,
+}
+
+struct Reader<R, F> {
+    io: R,
+    buf: BytesMut,
+    decoder: Decoder,
+    commands: mpsc::Sender<Command>,
+    handler: Arc<F>,
+    // streams which are still receiving the request
+    streams: HashMap<u32, RecvStream>,
+    // requests which are handled right now
+    in_flight: Arc<AtomicUsize>,
+    last_stream_id: u32,
+    window: i64,
+    // a header block which is continued in CONTINUATION frames
+    pending_headers: Option<PendingHeaders>,
+    settings_received: bool,
+}
+
+impl<R, F, Fut> Reader<R, F>
+where
+    R: AsyncRead + Unpin,
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ResponseBuilder> + Send + 'static,
+{
+    async fn run(&mut self) -> Result<(), Http2Error> {
+        if !self.read_until(PREFACE.len()).await {
+            return Ok(());
+        }
+        if &self.buf[..PREFACE.len()] != PREFACE {
+            return Err(Http2Error::Connection(
+                ErrorCode::ProtocolError,
+                "invalid connection preface",
+            ));
+        }
+        let _ = self.buf.split_to(PREFACE.len());
+
+        loop {
+            loop {
+                // frames which are invalid for their stream only reset the stream
+                let result = match Frame::parse(&mut self.buf, DEFAULT_MAX_FRAME_SIZE) {
+                    Ok(Some(frame)) => self.handle(frame).await,
+                    Ok(None) => break,
+                    Err(e) => Err(e),
+                };
+
+                match result {
+                    Ok(true) => {}
+                    Ok(false) => return Ok(()),
+                    Err(Http2Error::Stream(stream_id, code)) => {
+                        self.streams.remove(&stream_id);
+                        self.send(Command::Frame(Frame::RstStream { stream_id, code }))
+                            .await;
+                        self.send(Command::Close(stream_id)).await;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if !self.read_until(self.buf.len() + 1).await {
+                return Ok(());
+            }
+        }
+    }
+
+    // returns false once the connection was closed
+    async fn read_until(&mut self, length: usize) -> bool {
+        while self.buf.len() < length {
+            self.buf.reserve(READ_CHUNK_SIZE);
+            match self.io.read_buf(&mut self.buf).await {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {}
+            }
+        }
+        true
+    }
+
+    // only borrows the sender, so the future stays `Send` although the transport isn't `Sync`
+    fn send(&self, command: Command) -> impl Future<Output = ()> + '_ {
+        let commands = &self.commands;
+        async move {
+            // the writer only stops after a connection error or if the connection failed
+            let _ = commands.send(command).await;
+        }
+    }
+
+    // returns false if the client is going away
+    async fn handle(&mut self, frame: Frame) -> Result<bool, Http2Error> {
+        if !self.settings_received && !matches!(frame, Frame::Settings { ack: false, .. }) {
+            return Err(Http2Error::Connection(
+                ErrorCode::ProtocolError,
+                "expected SETTINGS after the preface",
+            ));
+        }
+
+        if self.pending_headers.is_some() && !matches!(frame, Frame::Continuation { .. }) {
+            return Err(Http2Error::Connection(
+                ErrorCode::ProtocolError,
+                "expected CONTINUATION",
+            ));
+        }
+
+        match frame {
+            Frame::Data {
+                stream_id,
+                data,
+                end_stream,
+                flow_length,
+            } => {
+                self.handle_data(stream_id, data, end_stream, flow_length)
+                    .await?
+            }
+            Frame::Headers {
+                stream_id,
+                block,
+                end_stream,
+                end_headers,
+                dependency,
+            } => {
+                self.pending_headers = Some(PendingHeaders {
+                    stream_id,
+                    block: BytesMut::from(&block[..]),
+                    end_stream,
+                    dependency,
+                });
+                if end_headers {
+                    self.handle_headers().await?;
+                }
+            }
+            Frame::Continuation {
+                stream_id,
+                block,
+                end_headers,
+            } => {
+                let pending = match self.pending_headers.as_mut() {
+                    Some(pending) if pending.stream_id == stream_id => pending,
+                    _ => {
+                        return Err(Http2Error::Connection(
+                            ErrorCode::ProtocolError,
+                            "unexpected CONTINUATION",
+                        ))
+                    }
+                };
+
+                if pending.block.len() + block.len() > MAX_HEADER_BLOCK_SIZE {
+                    return Err(Http2Error::Connection(
+                        ErrorCode::EnhanceYourCalm,
+                        "header block too large",
+                    ));
+                }
+                pending.block.extend_from_slice(&block);
+
+                if end_headers {
+                    self.handle_headers().await?;
+                }
+            }
+            Frame::Priority {
+                stream_id,
+                dependency,
+            } => {
+                if stream_id == dependency {
+                    return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+                }
+            }
+            Frame::RstStream { stream_id, .. } => {
+                if stream_id > self.last_stream_id {
+                    return Err(Http2Error::Connection(
+                        ErrorCode::ProtocolError,
+                        "RST_STREAM on idle stream",
+                    ));
+                }
+                self.streams.remove(&stream_id);
+                self.send(Command::Close(stream_id)).await;
+            }
+            Frame::Settings { ack, settings } => {
+                if !ack {
+                    self.settings_received = true;
+                    self.send(Command::Settings(settings)).await;
+                }
+            }
+            Frame::PushPromise { .. } => {
+                return Err(Http2Error::Connection(
+                    ErrorCode::ProtocolError,
+                    "clients can't push",
+                ));
+            }
+            Frame::Ping { ack, data } => {
+                if !ack {
+                    self.send(Command::Frame(Frame::Ping { ack: true, data }))
+                        .await;
+                }
+            }
+            Frame::GoAway { .. } => return Ok(false),
+            Frame::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                if increment == 0 {
+                    return Err(match stream_id {
+                        0 => Http2Error::Connection(
+                            ErrorCode::ProtocolError,
+                            "WINDOW_UPDATE with increment 0",
+                        ),
+                        _ => Http2Error::Stream(stream_id, ErrorCode::ProtocolError),
+                    });
+                }
+                if stream_id > self.last_stream_id {
+                    return Err(Http2Error::Connection(
+                        ErrorCode::ProtocolError,
+                        "WINDOW_UPDATE on idle stream",
+                    ));
+                }
+                self.send(Command::WindowUpdate {
+                    stream_id,
+                    increment,
+                })
+                .await;
+            }
+            Frame::Unknown { .. } => {}
+        }
+
+        Ok(true)
+    }
+
+    async fn handle_data(
+        &mut self,
+        stream_id: u32,
+        data: Bytes,
+        end_stream: bool,
+        flow_length: u32,
+    ) -> Result<(), Http2Error> {
+        // flow control applies to all data frames, even if the stream is gone
+        self.window -= flow_length as i64;
+        if self.window < 0 {
+            return Err(Http2Error::Connection(
+                ErrorCode::FlowControlError,
+                "connection window exceeded",
+            ));
+        }
+        // the body is buffered until it is complete, so the window is restored right away
+        if flow_length > 0 {
+            self.window += flow_length as i64;
+            self.send(Command::Frame(Frame::WindowUpdate {
+                stream_id: 0,
+                increment: flow_length,
+            }))
+            .await;
+        }
+
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None if stream_id > self.last_stream_id => {
+                return Err(Http2Error::Connection(
+                    ErrorCode::ProtocolError,
+                    "DATA on idle stream",
+                ))
+            }
+            None => return Err(Http2Error::Stream(stream_id, ErrorCode::StreamClosed)),
+        };
+
+        stream.window -= flow_length as i64;
+        if stream.window < 0 {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::FlowControlError));
+        }
+
+        if stream.request.body.len() + data.len() > MAX_BODY_SIZE {
+            self.streams.remove(&stream_id);
+            self.send(response(stream_id, StatusCode::PayloadTooLarge))
+                .await;
+            // the client stops sending the rest of the body
+            self.send(Command::Frame(Frame::RstStream {
+                stream_id,
+                code: ErrorCode::NoError,
+            }))
+            .await;
+            self.send(Command::Close(stream_id)).await;
+            return Ok(());
+        }
+        stream.request.body.extend_from_slice(&data);
+
+        if end_stream {
+            let stream = self.streams.remove(&stream_id).expect("stream to exist");
+            self.finish(stream_id, stream.request)?;
+        } else if flow_length > 0 {
+            stream.window += flow_length as i64;
+            self.send(Command::Frame(Frame::WindowUpdate {
+                stream_id,
+                increment: flow_length,
+            }))
+            .await;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_headers(&mut self) -> Result<(), Http2Error> {
+        let PendingHeaders {
+            stream_id,
+            block,
+            end_stream,
+            dependency,
+        } = self.pending_headers.take().expect("pending headers");
+
+        // the block has to be decoded in any case, so the dynamic table stays in sync,
+        // once decoding stopped early the connection can't be used anymore
+        let headers = self
+            .decoder
+            .decode(&block, MAX_HEADER_LIST_SIZE)
+            .map_err(|e| match e {
+                HpackError::HeaderListTooLarge(_) => {
+                    Http2Error::Connection(ErrorCode::EnhanceYourCalm, "header list too large")
+                }
+                _ => Http2Error::Connection(ErrorCode::CompressionError, "invalid header block"),
+            })?;
+
+        // trailers, which aren't passed on
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            let pseudo = headers.iter().any(|(name, _)| name.starts_with(b":"));
+            if !end_stream || pseudo {
+                return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+            return self.finish(stream_id, stream.request);
+        }
+
+        if stream_id <= self.last_stream_id {
+            return Err(Http2Error::Connection(
+                ErrorCode::StreamClosed,
+                "HEADERS on closed stream",
+            ));
+        }
+        if stream_id % 2 == 0 {
+            return Err(Http2Error::Connection(
+                ErrorCode::ProtocolError,
+                "client streams must be odd",
+            ));
+        }
+        self.last_stream_id = stream_id;
+
+        if dependency == Some(stream_id) {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+        }
+
+        let active = self.streams.len() + self.in_flight.load(Ordering::Relaxed);
+        if active >= MAX_CONCURRENT_STREAMS as usize {
+            return Err(Http2Error::Stream(stream_id, ErrorCode::RefusedStream));
+        }
+
+        self.send(Command::Open(stream_id)).await;
+
+        let request = build_request(headers)
+            .ok_or(Http2Error::Stream(stream_id, ErrorCode::ProtocolError))?;
+
+        if end_stream {
+            self.finish(stream_id, request)
+        } else {
+            self.streams.insert(
+                stream_id,
+                RecvStream {
+                    request,
+                    window: DEFAULT_WINDOW_SIZE as i64,
+                },
+            );
+            Ok(())
+        }
+    }
+
+    // the request was received completely
+    fn finish(&mut self, stream_id: u32, request: Request) -> Result<(), Http2Error> {
+        let content_length = request.headers.get_str("content-length").ok();
+        if let Some(content_length) = content_length {
+            if content_length.trim().parse::<usize>().ok() != Some(request.body.len()) {
+                return Err(Http2Error::Stream(stream_id, ErrorCode::ProtocolError));
+            }
+        }
+
+        self.dispatch(stream_id, request);
+        Ok(())
+    }
+
+    fn dispatch(&mut self, stream_id: u32, request: Request) {
+        let commands = self.commands.clone();
+        let handler = self.handler.clone();
+        let in_flight = self.in_flight.clone();
+        in_flight.fetch_add(1, Ordering::Relaxed);
+
+        tokio::spawn(async move {
+            if request.method.is_none() {
+                let _ = commands
+                    .send(response(stream_id, StatusCode::NotImplemented))
+                    .await;
+                in_flight.fetch_sub(1, Ordering::Relaxed);
+                return;
+            }
+
+            let head = request.method == Some(Method::HEAD);
+            let response = handler(request).await;
+
+            let mut headers = vec![(
+                ":status".to_string(),
+                response.status().as_u16().to_string(),
+            )];
+            for (name, value) in response.header_list(Some(response.body().len())) {
+                let name = name.to_ascii_lowercase();
+                if !CONNECTION_HEADERS.contains(&name.as_str()) {
+                    headers.push((name, value));
+                }
+            }
+
+            let body = match head {
+                true => Bytes::new(),
+                false => Bytes::copy_from_slice(response.body()),
+            };
+
+            let _ = commands
+                .send(Command::Respond {
+                    stream_id,
+                    headers,
+                    body,
+                })
+                .await;
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+// a response without a body, for requests which didn't reach the handler
+fn response(stream_id: u32, status: StatusCode) -> Command {
+    Command::Respond {
+        stream_id,
+        headers: vec![(":status".to_string(), status.as_u16().to_string())],
+        body: Bytes::new(),
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7540#section-8.1.2,
+// returns `None` for malformed requests
+fn build_request(headers: Vec<Header>) -> Option<Request> {
+    let mut request = Request::new();
+    let mut pseudo: BTreeMap<String, String> = BTreeMap::new();
+    let mut regular_seen = false;
+
+    for (name, value) in headers {
+        let name = String::from_utf8(name).ok()?;
+        if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return None;
+        }
+
+        if let Some(pseudo_name) = name.strip_prefix(':') {
+            let valid = matches!(pseudo_name, "method" | "scheme" | "authority" | "path");
+            if regular_seen || !valid {
+                return None;
+            }
+
+            let value = String::from_utf8(value).ok()?;
+            if pseudo.insert(name, value).is_some() {
+                return None;
+            }
+            continue;
+        }
+        regular_seen = true;
+
+        if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != b"trailers") {
+            return None;
+        }
+
+        // headers with the same name are combined
+        let value = match request.headers.get(&name) {
+            Ok(previous) => {
+                let separator: &[u8] = if name == "cookie" { b"; " } else { b", " };
+                [&previous[..], separator, &value[..]].concat()
+            }
+            Err(_) => value,
+        };
+        request.headers.insert(&name, &value);
+    }
+
+    let method = pseudo.get(":method")?;
+    // unsupported methods are answered with 501
+    request.method = Method::try_from(method.as_str()).ok();
+
+    if request.method == Some(Method::CONNECT) {
+        if pseudo.contains_key(":scheme") || pseudo.contains_key(":path") {
+            return None;
+        }
+        request.path = Some(pseudo.get(":authority")?.clone());
+    } else {
+        pseudo.get(":scheme")?;
+        let path = pseudo.get(":path").filter(|path| !path.is_empty())?;
+        request.path = Some(path.clone());
+    }
+
+    if let Some(authority) = pseudo.get(":authority") {
+        if !request.headers.contains("host") {
+            request.headers.insert("host", authority.as_bytes());
+        }
+    }
+
+    Some(request)
+}
+
+struct SendStream {
+    window: i64,
+    // the rest of the body, once the response headers were sent
+    body: Option<Bytes>,
+}
+
+struct Writer<W> {
+    io: W,
+    buf: BytesMut,
+    encoder: Encoder,
+    max_frame_size: u32,
+    initial_window: i64,
+    window: i64,
+    streams: BTreeMap<u32, SendStream>,
+    last_stream_id: u32,
+}
+
+impl<W> Writer<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn new(io: W) -> Self {
+        Writer {
+            io,
+            buf: BytesMut::new(),
+            encoder: Encoder::new(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            initial_window: DEFAULT_WINDOW_SIZE as i64,
+            window: DEFAULT_WINDOW_SIZE as i64,
+            streams: BTreeMap::new(),
+            last_stream_id: 0,
+        }
+    }
+
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) -> io::Result<()> {
+        // the server preface
+        Frame::Settings {
+            ack: false,
+            settings: Settings {
+                max_concurrent_streams: Some(MAX_CONCURRENT_STREAMS),
+                max_header_list_size: Some(MAX_HEADER_LIST_SIZE as u32),
+                ..Default::default()
+            },
+        }
+        .encode(&mut self.buf);
+
+        let mut closed = false;
+        while !closed {
+            self.io.write_all(&self.buf).await?;
+            self.io.flush().await?;
+            self.buf.clear();
+
+            let command = match commands.recv().await {
+                Some(command) => command,
+                None => break,
+            };
+
+            // handle everything that is queued before writing
+            closed = self.handle(command);
+            while !closed {
+                match commands.try_recv() {
+                    Ok(command) => closed = self.handle(command),
+                    Err(_) => break,
+                }
+            }
+
+            if !closed {
+                self.send_data();
+            }
+        }
+
+        self.io.write_all(&self.buf).await?;
+        let _ = self.io.shutdown().await;
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, settings: &Settings) {
+        if let Some(size) = settings.initial_window_size {
+            // the difference applies to all open streams
+            let delta = size as i64 - self.initial_window;
+            self.initial_window = size as i64;
+            for stream in self.streams.values_mut() {
+                stream.window += delta;
+            }
+        }
+
+        if let Some(size) = settings.max_frame_size {
+            self.max_frame_size = size;
+        }
+    }
+
+    // returns true once the connection has to be closed
+    fn handle(&mut self, command: Command) -> bool {
+        match command {
+            Command::Frame(frame) => frame.encode(&mut self.buf),
+            Command::Settings(settings) => {
+                self.apply_settings(&settings);
+                Frame::Settings {
+                    ack: true,
+                    settings: Settings::default(),
+                }
+                .encode(&mut self.buf);
+            }
+            Command::WindowUpdate {
+                stream_id: 0,
+                increment,
+            } => {
+                self.window += increment as i64;
+                if self.window > MAX_WINDOW_SIZE as i64 {
+                    self.go_away(ErrorCode::FlowControlError);
+                    return true;
+                }
+            }
+            Command::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                let overflow = match self.streams.get_mut(&stream_id) {
+                    Some(stream) => {
+                        stream.window += increment as i64;
+                        stream.window > MAX_WINDOW_SIZE as i64
+                    }
+                    None => false,
+                };
+
+                if overflow {
+                    self.streams.remove(&stream_id);
+                    Frame::RstStream {
+                        stream_id,
+                        code: ErrorCode::FlowControlError,
+                    }
+                    .encode(&mut self.buf);
+                }
+            }
+            Command::Open(stream_id) => {
+                self.last_stream_id = stream_id;
+                self.streams.insert(
+                    stream_id,
+                    SendStream {
+                        window: self.initial_window,
+                        body: None,
+                    },
+                );
+            }
+            Command::Close(stream_id) => {
+                self.streams.remove(&stream_id);
+            }
+            Command::Respond {
+                stream_id,
+                headers,
+                body,
+            } => self.send_headers(stream_id, headers, body),
+            Command::GoAway(code) => {
+                self.go_away(code);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn go_away(&mut self, code: ErrorCode) {
+        Frame::GoAway {
+            last_stream_id: self.last_stream_id,
+            code,
+            debug_data: Bytes::new(),
+        }
+        .encode(&mut self.buf);
+    }
+
+    fn send_headers(&mut self, stream_id: u32, headers: Vec<(String, String)>, body: Bytes) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) if stream.body.is_none() => stream,
+            // the stream was reset in the meantime
+            _ => return,
+        };
+
+        let mut block = BytesMut::new();
+        self.encoder.encode(
+            headers.iter().map(|(n, v)| (n.as_str(), v.as_str())),
+            &mut block,
+        );
+
+        let end_stream = body.is_empty();
+        let mut block = block.freeze();
+        let first = block.split_to(block.len().min(self.max_frame_size as usize));
+        Frame::Headers {
+            stream_id,
+            block: first,
+            end_stream,
+            end_headers: block.is_empty(),
+            dependency: None,
+        }
+        .encode(&mut self.buf);
+
+        // headers larger than a frame are continued
+        while !block.is_empty() {
+            let next = block.split_to(block.len().min(self.max_frame_size as usize));
+            Frame::Continuation {
+                stream_id,
+                block: next,
+                end_headers: block.is_empty(),
+            }
+            .encode(&mut self.buf);
+        }
+
+        if end_stream {
+            self.streams.remove(&stream_id);
+        } else {
+            stream.body = Some(body);
+        }
+    }
+
+    // sends as much of the pending bodies as the flow control windows allow
+    fn send_data(&mut self) {
+        let mut finished = vec![];
+
+        for (stream_id, stream) in self.streams.iter_mut() {
+            let body = match stream.body.as_mut() {
+                Some(body) => body,
+                None => continue,
+            };
+
+            while !body.is_empty() && stream.window > 0 && self.window > 0 {
+                let length = body
+                    .len()
+                    .min(stream.window as usize)
+                    .min(self.window as usize)
+                    .min(self.max_frame_size as usize);
+
+                let data = body.split_to(length);
+                stream.window -= length as i64;
+                self.window -= length as i64;
+
+                Frame::Data {
+                    stream_id: *stream_id,
+                    data,
+                    end_stream: body.is_empty(),
+                    flow_length: length as u32,
+                }
+                .encode(&mut self.buf);
+            }
+
+            if body.is_empty() {
+                finished.push(*stream_id);
+            }
+        }
+
+        for stream_id in finished {
+            self.streams.remove(&stream_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    // a client side connection which already sent the preface
+    struct Client {
+        io: DuplexStream,
+        buf: BytesMut,
+        encoder: Encoder,
+        decoder: Decoder,
+    }
+
+    impl Client {
+        async fn connect() -> Client {
+            let (server, io) = duplex(1 << 20);
+            tokio::spawn(serve_connection(
+                server,
+                &[],
+                |request: Request| async move {
+                    let mut response = ResponseBuilder::new();
+                    response.write(request.path.unwrap().as_bytes());
+                    response.write(&request.body);
+                    response
+                },
+            ));
+
+            let mut client = Client {
+                io,
+                buf: BytesMut::new(),
+                encoder: Encoder::new(),
+                decoder: Decoder::new(4096),
+            };
+            client.io.write_all(PREFACE).await.unwrap();
+            client
+                .send(Frame::Settings {
+                    ack: false,
+                    settings: Settings::default(),
+                })
+                .await;
+
+            let settings = client.next().await.unwrap();
+            assert!(matches!(settings, Frame::Settings { ack: false, .. }));
+            let ack = client.next().await.unwrap();
+            assert!(matches!(ack, Frame::Settings { ack: true, .. }));
+            client
+        }
+
+        async fn send(&mut self, frame: Frame) {
+            let mut buf = BytesMut::new();
+            frame.encode(&mut buf);
+            self.io.write_all(&buf).await.unwrap();
+        }
+
+        async fn request(&mut self, stream_id: u32, headers: &[(&str, &str)], end_stream: bool) {
+            let mut block = BytesMut::new();
+            self.encoder.encode(headers.iter().copied(), &mut block);
+            self.send(Frame::Headers {
+                stream_id,
+                block: block.freeze(),
+                end_stream,
+                end_headers: true,
+                dependency: None,
+            })
+            .await;
+        }
+
+        async fn next(&mut self) -> Option<Frame> {
+            loop {
+                if let Some(frame) = Frame::parse(&mut self.buf, DEFAULT_MAX_FRAME_SIZE).unwrap() {
+                    return Some(frame);
+                }
+                if self.io.read_buf(&mut self.buf).await.unwrap() == 0 {
+                    return None;
+                }
+            }
+        }
+
+        // the next frame which isn't a WINDOW_UPDATE
+        async fn next_frame(&mut self) -> Option<Frame> {
+            loop {
+                match self.next().await? {
+                    Frame::WindowUpdate { .. } => continue,
+                    frame => return Some(frame),
+                }
+            }
+        }
+
+        async fn response(&mut self) -> (Vec<Header>, Vec<u8>) {
+            let headers = match self.next_frame().await.unwrap() {
+                Frame::Headers { block, .. } => {
+                    self.decoder.decode(&block, MAX_HEADER_LIST_SIZE).unwrap()
+                }
+                frame => panic!("expected HEADERS, got {:?}", frame),
+            };
+
+            let mut body = vec![];
+            loop {
+                match self.next_frame().await.unwrap() {
+                    Frame::Data {
+                        data, end_stream, ..
+                    } => {
+                        body.extend_from_slice(&data);
+                        if end_stream {
+                            return (headers, body);
+                        }
+                    }
+                    frame => panic!("expected DATA, got {:?}", frame),
+                }
+            }
+        }
+    }
+
+    const GET: [(&str, &str); 4] = [
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/hello"),
+        (":authority", "localhost"),
+    ];
+
+    #[tokio::test]
+    async fn answer_requests() {
+        let mut client = Client::connect().await;
+        client.request(1, &GET, true).await;
+
+        let (headers, body) = client.response().await;
+        assert_eq!(headers[0], (b":status".to_vec(), b"200".to_vec()));
+        assert!(headers.contains(&(b"content-length".to_vec(), b"6".to_vec())));
+        assert_eq!(body, b"/hello");
+
+        // a request with a body in two frames
+        let mut post = GET;
+        post[0] = (":method", "POST");
+        client.request(3, &post, false).await;
+        for (data, end_stream) in [(" wor", false), ("ld", true)] {
+            client
+                .send(Frame::Data {
+                    stream_id: 3,
+                    data: Bytes::from_static(data.as_bytes()),
+                    end_stream,
+                    flow_length: data.len() as u32,
+                })
+                .await;
+        }
+
+        let (_, body) = client.response().await;
+        assert_eq!(body, b"/hello world");
+    }
+
+    #[tokio::test]
+    async fn answer_pings_and_respect_flow_control() {
+        let mut client = Client::connect().await;
+        client
+            .send(Frame::Ping {
+                ack: false,
+                data: *b"pingpong",
+            })
+            .await;
+        assert_eq!(
+            client.next().await,
+            Some(Frame::Ping {
+                ack: true,
+                data: *b"pingpong"
+            })
+        );
+
+        // only 2 bytes may be sent until the window is increased
+        client
+            .send(Frame::Settings {
+                ack: false,
+                settings: Settings {
+                    initial_window_size: Some(2),
+                    ..Default::default()
+                },
+            })
+            .await;
+        assert!(matches!(
+            client.next().await,
+            Some(Frame::Settings { ack: true, .. })
+        ));
+
+        client.request(1, &GET, true).await;
+        assert!(matches!(client.next().await, Some(Frame::Headers { .. })));
+        assert!(matches!(
+            client.next().await,
+            Some(Frame::Data { ref data, end_stream: false, .. }) if data == "/h"
+        ));
+
+        client
+            .send(Frame::WindowUpdate {
+                stream_id: 1,
+                increment: 100,
+            })
+            .await;
+        assert!(matches!(
+            client.next().await,
+            Some(Frame::Data { ref data, end_stream: true, .. }) if data == "ello"
+        ));
+    }
+
+    #[tokio::test]
+    async fn reset_malformed_requests() {
+        let mut client = Client::connect().await;
+
+        // uppercase header names are not allowed
+        let mut headers = GET.to_vec();
+        headers.push(("X-Upper", "1"));
+        client.request(1, &headers, true).await;
+        assert_eq!(
+            client.next_frame().await,
+            Some(Frame::RstStream {
+                stream_id: 1,
+                code: ErrorCode::ProtocolError
+            })
+        );
+
+        // the connection is still usable
+        client.request(3, &GET, true).await;
+        let (_, body) = client.response().await;
+        assert_eq!(body, b"/hello");
+    }
+
+    #[tokio::test]
+    async fn reset_streams_with_invalid_frames() {
+        let mut client = Client::connect().await;
+
+        // a PRIORITY frame with a 4 byte payload
+        let priority = [0, 0, 4, 0x2, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        client.io.write_all(&priority).await.unwrap();
+        assert_eq!(
+            client.next_frame().await,
+            Some(Frame::RstStream {
+                stream_id: 1,
+                code: ErrorCode::FrameSizeError
+            })
+        );
+
+        client.request(3, &GET, true).await;
+        let (_, body) = client.response().await;
+        assert_eq!(body, b"/hello");
+    }
+
+    #[tokio::test]
+    async fn reset_streams_with_too_large_bodies() {
+        let mut client = Client::connect().await;
+        let mut post = GET;
+        post[0] = (":method", "POST");
+        client.request(1, &post, false).await;
+
+        let chunk = Bytes::from(vec![0; DEFAULT_MAX_FRAME_SIZE as usize]);
+        for _ in 0..=MAX_BODY_SIZE / chunk.len() {
+            client
+                .send(Frame::Data {
+                    stream_id: 1,
+                    data: chunk.clone(),
+                    end_stream: false,
+                    flow_length: chunk.len() as u32,
+                })
+                .await;
+        }
+
+        let headers = match client.next_frame().await.unwrap() {
+            Frame::Headers { block, .. } => {
+                client.decoder.decode(&block, MAX_HEADER_LIST_SIZE).unwrap()
+            }
+            frame => panic!("expected HEADERS, got {:?}", frame),
+        };
+        assert_eq!(headers[0], (b":status".to_vec(), b"413".to_vec()));
+        assert_eq!(
+            client.next_frame().await,
+            Some(Frame::RstStream {
+                stream_id: 1,
+                code: ErrorCode::NoError
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn go_away_on_protocol_errors() {
+        let mut client = Client::connect().await;
+        client.request(2, &GET, true).await;
+        assert_eq!(
+            client.next_frame().await,
+            Some(Frame::GoAway {
+                last_stream_id: 0,
+                code: ErrorCode::ProtocolError,
+                debug_data: Bytes::new(),
+            })
+        );
+        assert_eq!(client.next().await, None);
+    }
+
+    #[test]
+    fn parse_upgrade_settings() {
+        let mut request = Request::new();
+        request.set_header("Connection", "Upgrade, HTTP2-Settings");
+        request.set_header("Upgrade", "h2c");
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100
+        request.set_header("HTTP2-Settings", "AAMAAABk");
+
+        assert_eq!(
+            h2c_upgrade_settings(&request),
+            Some(Settings {
+                max_concurrent_streams: Some(100),
+                ..Default::default()
+            })
+        );
+
+        request.set_header("Upgrade", "websocket");
+        assert_eq!(h2c_upgrade_settings(&request), None);
+    }
+}
@@ -0,0 +1,618 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+// https://datatracker.ietf.org/doc/html/rfc7540#section-4
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16384;
+pub const DEFAULT_WINDOW_SIZE: u32 = 65535;
+pub const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+const HEADER_LENGTH: usize = 9;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+/// https://datatracker.ietf.org/doc/html/rfc7540#section-7
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    NoError,
+    ProtocolError,
+    InternalError,
+    FlowControlError,
+    SettingsTimeout,
+    StreamClosed,
+    FrameSizeError,
+    RefusedStream,
+    Cancel,
+    CompressionError,
+    ConnectError,
+    EnhanceYourCalm,
+    InadequateSecurity,
+    Http11Required,
+    Unknown(u32),
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0x0 => ErrorCode::NoError,
+            0x1 => ErrorCode::ProtocolError,
+            0x2 => ErrorCode::InternalError,
+            0x3 => ErrorCode::FlowControlError,
+            0x4 => ErrorCode::SettingsTimeout,
+            0x5 => ErrorCode::StreamClosed,
+            0x6 => ErrorCode::FrameSizeError,
+            0x7 => ErrorCode::RefusedStream,
+            0x8 => ErrorCode::Cancel,
+            0x9 => ErrorCode::CompressionError,
+            0xa => ErrorCode::ConnectError,
+            0xb => ErrorCode::EnhanceYourCalm,
+            0xc => ErrorCode::InadequateSecurity,
+            0xd => ErrorCode::Http11Required,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::NoError => 0x0,
+            ErrorCode::ProtocolError => 0x1,
+            ErrorCode::InternalError => 0x2,
+            ErrorCode::FlowControlError => 0x3,
+            ErrorCode::SettingsTimeout => 0x4,
+            ErrorCode::StreamClosed => 0x5,
+            ErrorCode::FrameSizeError => 0x6,
+            ErrorCode::RefusedStream => 0x7,
+            ErrorCode::Cancel => 0x8,
+            ErrorCode::CompressionError => 0x9,
+            ErrorCode::ConnectError => 0xa,
+            ErrorCode::EnhanceYourCalm => 0xb,
+            ErrorCode::InadequateSecurity => 0xc,
+            ErrorCode::Http11Required => 0xd,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+/// Violations of https://datatracker.ietf.org/doc/html/rfc7540 by the peer.
+///
+/// Connection errors are answered with GOAWAY and close the connection,
+/// stream errors only reset the affected stream.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum Http2Error {
+    #[error("connection error {0:?}: {1}")]
+    Connection(ErrorCode, &'static str),
+    #[error("stream {0} error: {1:?}")]
+    Stream(u32, ErrorCode),
+}
+
+fn protocol_error(reason: &'static str) -> Http2Error {
+    Http2Error::Connection(ErrorCode::ProtocolError, reason)
+}
+
+fn frame_size_error(reason: &'static str) -> Http2Error {
+    Http2Error::Connection(ErrorCode::FrameSizeError, reason)
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc7540#section-6.5.2, only the settings
+/// contained in a SETTINGS frame are set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub header_table_size: Option<u32>,
+    pub enable_push: Option<bool>,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: Option<u32>,
+    pub max_frame_size: Option<u32>,
+    pub max_header_list_size: Option<u32>,
+}
+
+impl Settings {
+    pub fn parse(mut payload: &[u8]) -> Result<Self, Http2Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(frame_size_error("SETTINGS length must be a multiple of 6"));
+        }
+
+        let mut settings = Settings::default();
+        while payload.has_remaining() {
+            let id = payload.get_u16();
+            let value = payload.get_u32();
+
+            match id {
+                0x1 => settings.header_table_size = Some(value),
+                0x2 => {
+                    if value > 1 {
+                        return Err(protocol_error("invalid SETTINGS_ENABLE_PUSH"));
+                    }
+                    settings.enable_push = Some(value == 1)
+                }
+                0x3 => settings.max_concurrent_streams = Some(value),
+                0x4 => {
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(Http2Error::Connection(
+                            ErrorCode::FlowControlError,
+                            "SETTINGS_INITIAL_WINDOW_SIZE too large",
+                        ));
+                    }
+                    settings.initial_window_size = Some(value)
+                }
+                0x5 => {
+                    if !(DEFAULT_MAX_FRAME_SIZE..(1 << 24)).contains(&value) {
+                        return Err(protocol_error("invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    settings.max_frame_size = Some(value)
+                }
+                0x6 => settings.max_header_list_size = Some(value),
+                // unknown settings must be ignored
+                _ => {}
+            }
+        }
+
+        Ok(settings)
+    }
+
+    fn encode(&self, dst: &mut BytesMut) {
+        let settings = [
+            (0x1, self.header_table_size),
+            (0x2, self.enable_push.map(u32::from)),
+            (0x3, self.max_concurrent_streams),
+            (0x4, self.initial_window_size),
+            (0x5, self.max_frame_size),
+            (0x6, self.max_header_list_size),
+        ];
+
+        for (id, value) in settings {
+            if let Some(value) = value {
+                dst.put_u16(id);
+                dst.put_u32(value);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Data {
+        stream_id: u32,
+        data: Bytes,
+        end_stream: bool,
+        /// Length including padding, which counts towards flow control
+        flow_length: u32,
+    },
+    Headers {
+        stream_id: u32,
+        block: Bytes,
+        end_stream: bool,
+        end_headers: bool,
+        /// The stream this stream depends on
+        dependency: Option<u32>,
+    },
+    Priority {
+        stream_id: u32,
+        dependency: u32,
+    },
+    RstStream {
+        stream_id: u32,
+        code: ErrorCode,
+    },
+    Settings {
+        ack: bool,
+        settings: Settings,
+    },
+    PushPromise {
+        stream_id: u32,
+    },
+    Ping {
+        ack: bool,
+        data: [u8; 8],
+    },
+    GoAway {
+        last_stream_id: u32,
+        code: ErrorCode,
+        debug_data: Bytes,
+    },
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32,
+    },
+    Continuation {
+        stream_id: u32,
+        block: Bytes,
+        end_headers: bool,
+    },
+    /// Frames of unknown types must be ignored
+    Unknown {
+        kind: u8,
+        stream_id: u32,
+    },
+}
+
+impl Frame {
+    /// Parses the next frame from `buf`, returns `None` if it isn't complete yet
+    pub fn parse(buf: &mut BytesMut, max_frame_size: u32) -> Result<Option<Frame>, Http2Error> {
+        if buf.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let length = (buf[0] as u32) << 16 | (buf[1] as u32) << 8 | buf[2] as u32;
+        if length > max_frame_size {
+            return Err(frame_size_error(
+                "frame larger than SETTINGS_MAX_FRAME_SIZE",
+            ));
+        }
+        if buf.len() < HEADER_LENGTH + length as usize {
+            return Ok(None);
+        }
+
+        let mut header = buf.split_to(HEADER_LENGTH);
+        header.advance(3);
+        let kind = header.get_u8();
+        let flags = header.get_u8();
+        // the reserved bit is ignored
+        let stream_id = header.get_u32() & MAX_WINDOW_SIZE;
+        let payload = buf.split_to(length as usize).freeze();
+
+        Frame::parse_payload(kind, flags, stream_id, payload).map(Some)
+    }
+
+    fn parse_payload(
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        mut payload: Bytes,
+    ) -> Result<Frame, Http2Error> {
+        let connection_frame = matches!(kind, SETTINGS | PING | GOAWAY);
+        if connection_frame && stream_id != 0 {
+            return Err(protocol_error("frame must be sent on stream 0"));
+        }
+
+        let stream_frame = matches!(
+            kind,
+            DATA | HEADERS | PRIORITY | RST_STREAM | PUSH_PROMISE | CONTINUATION
+        );
+        if stream_frame && stream_id == 0 {
+            return Err(protocol_error("frame must not be sent on stream 0"));
+        }
+
+        let frame = match kind {
+            DATA => {
+                let flow_length = payload.len() as u32;
+                let data = strip_padding(flags, payload)?;
+                Frame::Data {
+                    stream_id,
+                    data,
+                    end_stream: flags & FLAG_END_STREAM != 0,
+                    flow_length,
+                }
+            }
+            HEADERS => {
+                let mut block = strip_padding(flags, payload)?;
+                let mut dependency = None;
+                if flags & FLAG_PRIORITY != 0 {
+                    if block.len() < 5 {
+                        return Err(frame_size_error("HEADERS too short for priority"));
+                    }
+                    // stream dependency and weight
+                    dependency = Some(block.get_u32() & MAX_WINDOW_SIZE);
+                    block.advance(1);
+                }
+
+                Frame::Headers {
+                    stream_id,
+                    block,
+                    end_stream: flags & FLAG_END_STREAM != 0,
+                    end_headers: flags & FLAG_END_HEADERS != 0,
+                    dependency,
+                }
+            }
+            PRIORITY => {
+                if payload.len() != 5 {
+                    return Err(Http2Error::Stream(stream_id, ErrorCode::FrameSizeError));
+                }
+                Frame::Priority {
+                    stream_id,
+                    dependency: payload.get_u32() & MAX_WINDOW_SIZE,
+                }
+            }
+            RST_STREAM => {
+                if payload.len() != 4 {
+                    return Err(frame_size_error("RST_STREAM must be 4 bytes"));
+                }
+                Frame::RstStream {
+                    stream_id,
+                    code: ErrorCode::from(payload.get_u32()),
+                }
+            }
+            SETTINGS => {
+                let ack = flags & FLAG_ACK != 0;
+                if ack && !payload.is_empty() {
+                    return Err(frame_size_error("SETTINGS ack must be empty"));
+                }
+                Frame::Settings {
+                    ack,
+                    settings: Settings::parse(&payload)?,
+                }
+            }
+            PUSH_PROMISE => Frame::PushPromise { stream_id },
+            PING => {
+                if payload.len() != 8 {
+                    return Err(frame_size_error("PING must be 8 bytes"));
+                }
+                let mut data = [0; 8];
+                payload.copy_to_slice(&mut data);
+                Frame::Ping {
+                    ack: flags & FLAG_ACK != 0,
+                    data,
+                }
+            }
+            GOAWAY => {
+                if payload.len() < 8 {
+                    return Err(frame_size_error("GOAWAY too short"));
+                }
+                Frame::GoAway {
+                    last_stream_id: payload.get_u32() & MAX_WINDOW_SIZE,
+                    code: ErrorCode::from(payload.get_u32()),
+                    debug_data: payload,
+                }
+            }
+            WINDOW_UPDATE => {
+                if payload.len() != 4 {
+                    return Err(frame_size_error("WINDOW_UPDATE must be 4 bytes"));
+                }
+                Frame::WindowUpdate {
+                    stream_id,
+                    increment: payload.get_u32() & MAX_WINDOW_SIZE,
+                }
+            }
+            CONTINUATION => Frame::Continuation {
+                stream_id,
+                block: payload,
+                end_headers: flags & FLAG_END_HEADERS != 0,
+            },
+            kind => Frame::Unknown { kind, stream_id },
+        };
+
+        Ok(frame)
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) {
+        let mut payload = BytesMut::new();
+
+        let (kind, flags, stream_id) = match self {
+            Frame::Data {
+                stream_id,
+                data,
+                end_stream,
+                ..
+            } => {
+                payload.put_slice(data);
+                (DATA, flag(*end_stream, FLAG_END_STREAM), *stream_id)
+            }
+            Frame::Headers {
+                stream_id,
+                block,
+                end_stream,
+                end_headers,
+                dependency,
+            } => {
+                let mut flags =
+                    flag(*end_stream, FLAG_END_STREAM) | flag(*end_headers, FLAG_END_HEADERS);
+                if let Some(dependency) = dependency {
+                    flags |= FLAG_PRIORITY;
+                    payload.put_u32(*dependency);
+                    // default weight
+                    payload.put_u8(15);
+                }
+                payload.put_slice(block);
+                (HEADERS, flags, *stream_id)
+            }
+            Frame::Priority {
+                stream_id,
+                dependency,
+            } => {
+                payload.put_u32(*dependency);
+                payload.put_u8(15);
+                (PRIORITY, 0, *stream_id)
+            }
+            Frame::RstStream { stream_id, code } => {
+                payload.put_u32((*code).into());
+                (RST_STREAM, 0, *stream_id)
+            }
+            Frame::Settings { ack, settings } => {
+                settings.encode(&mut payload);
+                (SETTINGS, flag(*ack, FLAG_ACK), 0)
+            }
+            Frame::PushPromise { stream_id } => (PUSH_PROMISE, 0, *stream_id),
+            Frame::Ping { ack, data } => {
+                payload.put_slice(data);
+                (PING, flag(*ack, FLAG_ACK), 0)
+            }
+            Frame::GoAway {
+                last_stream_id,
+                code,
+                debug_data,
+            } => {
+                payload.put_u32(*last_stream_id);
+                payload.put_u32((*code).into());
+                payload.put_slice(debug_data);
+                (GOAWAY, 0, 0)
+            }
+            Frame::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                payload.put_u32(*increment);
+                (WINDOW_UPDATE, 0, *stream_id)
+            }
+            Frame::Continuation {
+                stream_id,
+                block,
+                end_headers,
+            } => {
+                payload.put_slice(block);
+                (
+                    CONTINUATION,
+                    flag(*end_headers, FLAG_END_HEADERS),
+                    *stream_id,
+                )
+            }
+            Frame::Unknown { kind, stream_id } => (*kind, 0, *stream_id),
+        };
+
+        let length = payload.len() as u32;
+        dst.put_slice(&length.to_be_bytes()[1..]);
+        dst.put_u8(kind);
+        dst.put_u8(flags);
+        dst.put_u32(stream_id);
+        dst.put(payload);
+    }
+}
+
+fn flag(set: bool, flag: u8) -> u8 {
+    if set {
+        flag
+    } else {
+        0
+    }
+}
+
+fn strip_padding(flags: u8, mut payload: Bytes) -> Result<Bytes, Http2Error> {
+    if flags & FLAG_PADDED == 0 {
+        return Ok(payload);
+    }
+
+    if payload.is_empty() {
+        return Err(frame_size_error("padded frame without pad length"));
+    }
+    let padding = payload.get_u8() as usize;
+    if padding > payload.len() {
+        return Err(protocol_error("padding longer than the payload"));
+    }
+
+    payload.truncate(payload.len() - padding);
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: Frame) {
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf);
+        assert_eq!(
+            Frame::parse(&mut buf, DEFAULT_MAX_FRAME_SIZE).unwrap(),
+            Some(frame)
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_and_parse_frames() {
+        round_trip(Frame::Data {
+            stream_id: 1,
+            data: Bytes::from_static(b"hello"),
+            end_stream: true,
+            flow_length: 5,
+        });
+        round_trip(Frame::Headers {
+            stream_id: 3,
+            block: Bytes::from_static(&[0x82]),
+            end_stream: false,
+            end_headers: true,
+            dependency: Some(1),
+        });
+        round_trip(Frame::Settings {
+            ack: false,
+            settings: Settings {
+                max_concurrent_streams: Some(100),
+                initial_window_size: Some(1 << 20),
+                ..Default::default()
+            },
+        });
+        round_trip(Frame::Ping {
+            ack: true,
+            data: *b"12345678",
+        });
+        round_trip(Frame::GoAway {
+            last_stream_id: 7,
+            code: ErrorCode::EnhanceYourCalm,
+            debug_data: Bytes::from_static(b"bye"),
+        });
+        round_trip(Frame::WindowUpdate {
+            stream_id: 0,
+            increment: 1024,
+        });
+        round_trip(Frame::RstStream {
+            stream_id: 5,
+            code: ErrorCode::Cancel,
+        });
+    }
+
+    #[test]
+    fn wait_for_complete_frames() {
+        let mut buf = BytesMut::from(&[0, 0, 5, DATA, 0, 0, 0, 0, 1, b'h'][..]);
+        assert_eq!(Frame::parse(&mut buf, DEFAULT_MAX_FRAME_SIZE), Ok(None));
+        assert_eq!(buf.len(), 10);
+    }
+
+    #[test]
+    fn strip_data_padding() {
+        let mut buf =
+            BytesMut::from(&[0, 0, 5, DATA, FLAG_PADDED, 0, 0, 0, 1, 2, b'h', b'i', 0, 0][..]);
+        let frame = Frame::parse(&mut buf, DEFAULT_MAX_FRAME_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            frame,
+            Frame::Data {
+                stream_id: 1,
+                data: Bytes::from_static(b"hi"),
+                end_stream: false,
+                flow_length: 5,
+            }
+        );
+
+        let mut buf = BytesMut::from(&[0, 0, 2, DATA, FLAG_PADDED, 0, 0, 0, 1, 2, b'h'][..]);
+        assert_eq!(
+            Frame::parse(&mut buf, DEFAULT_MAX_FRAME_SIZE),
+            Err(protocol_error("padding longer than the payload"))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_frames() {
+        let mut buf = BytesMut::from(&[0, 0x40, 1, DATA, 0, 0, 0, 0, 1][..]);
+        assert!(matches!(
+            Frame::parse(&mut buf, DEFAULT_MAX_FRAME_SIZE),
+            Err(Http2Error::Connection(ErrorCode::FrameSizeError, _))
+        ));
+
+        let mut buf = BytesMut::from(&[0, 0, 0, SETTINGS, 0, 0, 0, 0, 1][..]);
+        assert!(matches!(
+            Frame::parse(&mut buf, DEFAULT_MAX_FRAME_SIZE),
+            Err(Http2Error::Connection(ErrorCode::ProtocolError, _))
+        ));
+
+        let mut buf = BytesMut::from(&[0, 0, 6, SETTINGS, 0, 0, 0, 0, 0, 0, 4, 0x80, 0, 0, 0][..]);
+        assert!(matches!(
+            Frame::parse(&mut buf, DEFAULT_MAX_FRAME_SIZE),
+            Err(Http2Error::Connection(ErrorCode::FlowControlError, _))
+        ));
+    }
+}
@@ -0,0 +1,483 @@
+use bytes::{BufMut, BytesMut};
+use std::collections::VecDeque;
+use thiserror::Error;
+
+use super::huffman;
+
+// https://datatracker.ietf.org/doc/html/rfc7541
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum HpackError {
+    #[error("header block ended unexpectedly")]
+    UnexpectedEnd,
+    #[error("integer too large")]
+    IntegerOverflow,
+    #[error("invalid table index: {0}")]
+    InvalidIndex(usize),
+    #[error("invalid huffman code")]
+    InvalidHuffman,
+    #[error("dynamic table size update not at the start of the header block")]
+    UnexpectedSizeUpdate,
+    #[error("dynamic table size {0} is larger than allowed")]
+    InvalidSizeUpdate(usize),
+    #[error("header list is larger than {0} bytes")]
+    HeaderListTooLarge(usize),
+}
+
+pub type Header = (Vec<u8>, Vec<u8>);
+
+// https://datatracker.ietf.org/doc/html/rfc7541#appendix-A
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// every entry is counted with an overhead of 32 bytes
+const ENTRY_OVERHEAD: usize = 32;
+
+struct DynamicTable {
+    entries: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+impl DynamicTable {
+    fn new(max_size: usize) -> Self {
+        DynamicTable {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    // entries larger than the table empty it and aren't added
+    fn insert(&mut self, header: Header) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.size += size;
+        self.entries.push_front(header);
+        self.evict();
+    }
+
+    fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let (name, value) = match self.entries.pop_back() {
+                Some(entry) => entry,
+                None => break,
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// Decodes header blocks, the dynamic table is shared by all blocks of a connection
+pub struct Decoder {
+    table: DynamicTable,
+    // SETTINGS_HEADER_TABLE_SIZE we announced
+    max_table_size: usize,
+}
+
+impl Decoder {
+    pub fn new(max_table_size: usize) -> Self {
+        Decoder {
+            table: DynamicTable::new(max_table_size),
+            max_table_size,
+        }
+    }
+
+    /// Decodes a header block, failing as soon as the decoded headers exceed `max_list_size`,
+    /// counted like SETTINGS_MAX_HEADER_LIST_SIZE. The dynamic table is out of sync after an error.
+    pub fn decode(
+        &mut self,
+        mut src: &[u8],
+        max_list_size: usize,
+    ) -> Result<Vec<Header>, HpackError> {
+        let mut headers = vec![];
+        let mut list_size = 0;
+
+        while let Some(first) = src.first().copied() {
+            let header = if first & 0x80 != 0 {
+                // indexed header field
+                let index = decode_integer(&mut src, 7)?;
+                self.get(index)?
+            } else if first & 0x40 != 0 {
+                // literal header field with incremental indexing
+                let header = self.decode_literal(&mut src, 6)?;
+                self.table.insert(header.clone());
+                header
+            } else if first & 0x20 != 0 {
+                // dynamic table size update
+                if !headers.is_empty() {
+                    return Err(HpackError::UnexpectedSizeUpdate);
+                }
+                let size = decode_integer(&mut src, 5)?;
+                if size > self.max_table_size {
+                    return Err(HpackError::InvalidSizeUpdate(size));
+                }
+                self.table.set_max_size(size);
+                continue;
+            } else {
+                // literal header field without indexing or never indexed
+                self.decode_literal(&mut src, 4)?
+            };
+
+            list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            if list_size > max_list_size {
+                return Err(HpackError::HeaderListTooLarge(max_list_size));
+            }
+            headers.push(header);
+        }
+
+        Ok(headers)
+    }
+
+    fn decode_literal(&self, src: &mut &[u8], prefix: u8) -> Result<Header, HpackError> {
+        let name = match decode_integer(src, prefix)? {
+            0 => decode_string(src)?,
+            index => self.get(index)?.0,
+        };
+        let value = decode_string(src)?;
+        Ok((name, value))
+    }
+
+    fn get(&self, index: usize) -> Result<Header, HpackError> {
+        match index {
+            0 => Err(HpackError::InvalidIndex(index)),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self
+                .table
+                .entries
+                .get(index - 62)
+                .cloned()
+                .ok_or(HpackError::InvalidIndex(index)),
+        }
+    }
+}
+
+/// Encodes header blocks without using the dynamic table
+#[derive(Default)]
+pub struct Encoder {
+    size_update_sent: bool,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder::default()
+    }
+
+    pub fn encode<'a>(
+        &mut self,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        dst: &mut BytesMut,
+    ) {
+        // the dynamic table is never used, so it is resized to 0 once,
+        // that way later changes of SETTINGS_HEADER_TABLE_SIZE don't affect us
+        if !self.size_update_sent {
+            encode_integer(0, 5, 0x20, dst);
+            self.size_update_sent = true;
+        }
+
+        for (name, value) in headers {
+            let exact = STATIC_TABLE.iter().position(|e| *e == (name, value));
+            if let Some(index) = exact {
+                encode_integer(index + 1, 7, 0x80, dst);
+                continue;
+            }
+
+            // literal without indexing, using the static table for the name if possible
+            match STATIC_TABLE.iter().position(|(n, _)| *n == name) {
+                Some(index) => encode_integer(index + 1, 4, 0, dst),
+                None => {
+                    dst.put_u8(0);
+                    encode_string(name.as_bytes(), dst);
+                }
+            }
+            encode_string(value.as_bytes(), dst);
+        }
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7541#section-5.1
+fn decode_integer(src: &mut &[u8], prefix: u8) -> Result<usize, HpackError> {
+    let (first, rest) = src.split_first().ok_or(HpackError::UnexpectedEnd)?;
+    *src = rest;
+
+    let max = (1 << prefix) - 1;
+    let mut value = (*first & max) as usize;
+    if value < max as usize {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let (byte, rest) = src.split_first().ok_or(HpackError::UnexpectedEnd)?;
+        *src = rest;
+
+        // nothing we accept needs more than 28 bits
+        if shift > 21 {
+            return Err(HpackError::IntegerOverflow);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(value: usize, prefix: u8, flags: u8, dst: &mut BytesMut) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        dst.put_u8(flags | value as u8);
+        return;
+    }
+
+    dst.put_u8(flags | max as u8);
+    let mut value = value - max;
+    while value >= 128 {
+        dst.put_u8((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+    dst.put_u8(value as u8);
+}
+
+fn decode_string(src: &mut &[u8]) -> Result<Vec<u8>, HpackError> {
+    let huffman = src.first().ok_or(HpackError::UnexpectedEnd)? & 0x80 != 0;
+    let length = decode_integer(src, 7)?;
+    if src.len() < length {
+        return Err(HpackError::UnexpectedEnd);
+    }
+
+    let (string, rest) = src.split_at(length);
+    *src = rest;
+
+    if huffman {
+        huffman::decode(string)
+    } else {
+        Ok(string.to_vec())
+    }
+}
+
+// uses the huffman code if it is shorter
+fn encode_string(src: &[u8], dst: &mut BytesMut) {
+    let huffman_len = huffman::encoded_len(src);
+    if huffman_len < src.len() {
+        encode_integer(huffman_len, 7, 0x80, dst);
+        let mut encoded = Vec::with_capacity(huffman_len);
+        huffman::encode(src, &mut encoded);
+        dst.put_slice(&encoded);
+    } else {
+        encode_integer(src.len(), 7, 0, dst);
+        dst.put_slice(src);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_LIST_SIZE: usize = 16384;
+
+    fn headers(headers: &[(&str, &str)]) -> Vec<Header> {
+        headers
+            .iter()
+            .map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc7541#appendix-C.4
+    #[test]
+    fn decode_rfc_requests() {
+        let mut decoder = Decoder::new(4096);
+
+        let block = [
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab,
+            0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(
+            decoder.decode(&block, MAX_LIST_SIZE).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+
+        let block = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&block, MAX_LIST_SIZE).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+
+        let block = [
+            0x82, 0x87, 0x85, 0xbf, 0x40, 0x88, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xa9, 0x7d, 0x7f,
+            0x89, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&block, MAX_LIST_SIZE).unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.table.size, 164);
+    }
+
+    #[test]
+    fn evict_entries() {
+        let mut decoder = Decoder::new(4096);
+        // size update to 50 bytes, then two literals with indexing
+        let block = [
+            0x3f, 0x13, 0x40, 0x01, b'a', 0x01, b'b', 0x40, 0x01, b'c', 0x01, b'd', 0xbe,
+        ];
+        assert_eq!(
+            decoder.decode(&block, MAX_LIST_SIZE).unwrap(),
+            headers(&[("a", "b"), ("c", "d"), ("c", "d")])
+        );
+        assert_eq!(decoder.table.entries.len(), 1);
+        assert_eq!(
+            decoder.decode(&[0xbf], MAX_LIST_SIZE),
+            Err(HpackError::InvalidIndex(63))
+        );
+    }
+
+    #[test]
+    fn reject_invalid_blocks() {
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(
+            decoder.decode(&[0x80], MAX_LIST_SIZE),
+            Err(HpackError::InvalidIndex(0))
+        );
+        assert_eq!(
+            decoder.decode(&[0x41, 0x05, b'a'], MAX_LIST_SIZE),
+            Err(HpackError::UnexpectedEnd)
+        );
+        assert_eq!(
+            decoder.decode(&[0x3f, 0xe2, 0x1f], MAX_LIST_SIZE),
+            Err(HpackError::InvalidSizeUpdate(4097))
+        );
+        assert_eq!(
+            decoder.decode(&[0x82, 0x20], MAX_LIST_SIZE),
+            Err(HpackError::UnexpectedSizeUpdate)
+        );
+        assert_eq!(
+            decoder.decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x0f], MAX_LIST_SIZE),
+            Err(HpackError::IntegerOverflow)
+        );
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let list = [
+            (":status", "200"),
+            (":status", "418"),
+            ("content-type", "text/html"),
+            ("x-powered-by", "webserver-from-scratch"),
+            ("x-empty", ""),
+        ];
+
+        let mut encoder = Encoder::new();
+        let mut block = BytesMut::new();
+        encoder.encode(list, &mut block);
+        assert_eq!(block[..2], [0x20, 0x88]);
+
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(
+            decoder.decode(&block, MAX_LIST_SIZE).unwrap(),
+            headers(&list)
+        );
+    }
+
+    #[test]
+    fn reject_large_header_lists() {
+        let mut decoder = Decoder::new(4096);
+        // a 100 byte entry is added to the dynamic table once and then referenced repeatedly
+        let mut block = vec![0x40, 0x01, b'a', 0x63];
+        block.extend_from_slice(&[b'b'; 99]);
+        block.resize(block.len() + 1000, 0xbe);
+
+        assert_eq!(
+            decoder.decode(&block, 1000),
+            Err(HpackError::HeaderListTooLarge(1000))
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! HTTP/2 (RFC 7540) with HPACK header compression (RFC 7541).
+//!
+//! Every stream is dispatched through the same middlewares as http/1.1 requests,
+//! `request.version` is `None` for them and the socket can't be taken over.
+
+mod connection;
+mod frame;
+mod hpack;
+mod huffman;
+
+pub use connection::{h2c_upgrade_settings, serve_connection, serve_upgraded_connection};
+pub use frame::{ErrorCode, Frame, Http2Error, Settings, PREFACE};
+pub use hpack::{Decoder, Encoder, Header, HpackError};
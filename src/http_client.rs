@@ -0,0 +1,813 @@
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use parking_lot::Mutex;
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::{
+    http_request::{Method, Request},
+    http_response::Response,
+    StatusCode,
+};
+
+const READ_BUFFER_SIZE: usize = 16384;
+const MAX_HEAD_SIZE: usize = 65536;
+const MAX_BODY_SIZE: usize = 64 << 20;
+
+// sent to the original host only, not to the target of a redirect to another host
+const CREDENTIAL_HEADERS: [&str; 3] = ["Authorization", "Cookie", "Proxy-Authorization"];
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("invalid url")]
+    Url,
+    #[error("unsupported url scheme `{0}`")]
+    Scheme(String),
+    #[error("request timed out")]
+    Timeout,
+    #[error("too many redirects")]
+    TooManyRedirects,
+    #[error("connection closed before the response was complete")]
+    ConnectionClosed,
+    #[error("response head too large")]
+    HeadTooLarge,
+    #[error("response body too large")]
+    BodyTooLarge,
+    #[error("invalid content length")]
+    ContentLength,
+    #[error("invalid chunked encoding")]
+    Chunked,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    // Parses urls in the form of `http://host[:port][/path]`
+    pub fn parse(url: &str) -> Result<Self, ClientError> {
+        let (scheme, rest) = url.split_once("://").ok_or(ClientError::Url)?;
+        if !scheme.eq_ignore_ascii_case("http") {
+            return Err(ClientError::Scheme(scheme.to_string()));
+        }
+
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+
+        // strip the fragment, it's never sent to the server
+        let path = match path.split_once('#') {
+            Some((path, _)) => path.to_string(),
+            None => path,
+        };
+
+        // ipv6 addresses are wrapped in brackets, e.g `[::1]:8080`
+        let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+            let (host, port) = rest.split_once(']').ok_or(ClientError::Url)?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| ClientError::Url)?,
+            None => 80,
+        };
+
+        if host.is_empty() || !path.bytes().all(crate::tokens::is_uri_token) {
+            return Err(ClientError::Url);
+        }
+
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+
+    /// The value of the `Host` header, e.g `localhost:8080`
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+
+        match self.port {
+            80 => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+
+    // Resolves the target of a redirect relative to this url
+    pub fn join(&self, location: &str) -> Result<Url, ClientError> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+
+        if let Some(location) = location.strip_prefix("//") {
+            return Url::parse(&format!("http://{}", location));
+        }
+
+        let mut url = self.clone();
+        url.path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let path = self.path.split('?').next().unwrap_or("/");
+            let base = &path[..path.rfind('/').map(|i| i + 1).unwrap_or(0)];
+            format!("{}{}", base, location)
+        };
+
+        Ok(url)
+    }
+}
+
+/// A single keep-alive connection to a server.
+///
+/// Bytes that were read past the end of a response are kept around, so
+/// responses to pipelined requests can be read one after another.
+pub struct Connection {
+    stream: TcpStream,
+    buffer: BytesMut,
+    max_body_size: usize,
+    // total number of bytes received, to tell whether the server answered at all
+    bytes_read: usize,
+}
+
+impl Connection {
+    pub async fn connect(host: &str, port: u16) -> Result<Self> {
+        let stream = TcpStream::connect((host, port)).await?;
+        stream.set_nodelay(true)?;
+
+        Ok(Connection {
+            stream,
+            buffer: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            max_body_size: MAX_BODY_SIZE,
+            bytes_read: 0,
+        })
+    }
+
+    /// Larger response bodies are rejected, defaults to 64 MiB
+    pub fn max_body_size(&mut self, size: usize) -> &mut Self {
+        self.max_body_size = size;
+        self
+    }
+
+    pub async fn send(&mut self, request: &Request) -> Result<()> {
+        self.send_bytes(&request.build()).await
+    }
+
+    /// Sends already serialized requests, e.g multiple pipelined requests at once
+    pub async fn send_bytes(&mut self, requests: &[u8]) -> Result<()> {
+        self.stream.write_all(requests).await?;
+        Ok(())
+    }
+
+    /// Reads the next response from the connection, including its body.
+    /// Also returns whether the connection can be reused afterwards.
+    pub async fn read_response(&mut self, method: &Method) -> Result<(Response, bool)> {
+        let mut response = loop {
+            let head = self.read_head().await?;
+            let mut response = Response::new();
+            response.parse(head)?;
+
+            // informational responses (e.g `100 Continue`) are followed by the actual response
+            if !(100..200).contains(&response.status_code.as_u16()) {
+                break response;
+            }
+        };
+
+        let mut keep_alive = if response.version == Some(0) {
+            response.headers.contains_token("Connection", "keep-alive")
+        } else {
+            !response.headers.contains_token("Connection", "close")
+        };
+
+        let status = response.status_code.as_u16();
+        let has_body = *method != Method::HEAD && status != 204 && status != 304;
+
+        if !has_body {
+            return Ok((response, keep_alive));
+        }
+
+        if response.headers.contains_token("Transfer-Encoding", "chunked") {
+            response.body = self.read_chunked_body().await?;
+        } else if let Ok(length) = response.headers.get_str("Content-Length") {
+            let length: usize = length
+                .trim()
+                .parse()
+                .map_err(|_| ClientError::ContentLength)?;
+            if length > self.max_body_size {
+                return Err(ClientError::BodyTooLarge.into());
+            }
+
+            self.fill(length).await?;
+            response.body = self.buffer.split_to(length).to_vec();
+        } else {
+            // without a length the body is delimited by the server closing the connection
+            while self.read_more().await? != 0 {
+                if self.buffer.len() > self.max_body_size {
+                    return Err(ClientError::BodyTooLarge.into());
+                }
+            }
+            response.body = self.buffer.split().to_vec();
+            keep_alive = false;
+        }
+
+        Ok((response, keep_alive))
+    }
+
+    /// Reads the next response head without a body, e.g for `101 Switching Protocols`
+    pub async fn read_response_head(&mut self) -> Result<Response> {
+        let head = self.read_head().await?;
+        let mut response = Response::new();
+        response.parse(head)?;
+        Ok(response)
+    }
+
+    /// Takes over the underlying stream, together with any bytes read past the last response
+    pub fn into_inner(self) -> (TcpStream, BytesMut) {
+        (self.stream, self.buffer)
+    }
+
+    // reads until the end of the response head and returns it
+    async fn read_head(&mut self) -> Result<Bytes> {
+        let mut searched = 0;
+        loop {
+            if let Some(end) = find_head_end(&self.buffer, searched) {
+                return Ok(self.buffer.split_to(end).freeze());
+            }
+
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(ClientError::HeadTooLarge.into());
+            }
+
+            // the terminator might span the previous and the next read
+            searched = self.buffer.len().saturating_sub(3);
+            if self.read_more().await? == 0 {
+                return Err(ClientError::ConnectionClosed.into());
+            }
+        }
+    }
+
+    async fn read_chunked_body(&mut self) -> Result<Vec<u8>> {
+        let mut decoder = ChunkedDecoder::new();
+        let mut body = vec![];
+
+        while !decoder.decode(&mut self.buffer, &mut body)? {
+            if body.len() > self.max_body_size {
+                return Err(ClientError::BodyTooLarge.into());
+            }
+            if self.read_more().await? == 0 {
+                return Err(ClientError::ConnectionClosed.into());
+            }
+        }
+
+        if body.len() > self.max_body_size {
+            return Err(ClientError::BodyTooLarge.into());
+        }
+        Ok(body)
+    }
+
+    // makes sure at least `length` bytes are buffered
+    async fn fill(&mut self, length: usize) -> Result<()> {
+        self.buffer.reserve(length.saturating_sub(self.buffer.len()));
+        while self.buffer.len() < length {
+            if self.read_more().await? == 0 {
+                return Err(ClientError::ConnectionClosed.into());
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_more(&mut self) -> Result<usize> {
+        if self.buffer.capacity() - self.buffer.len() < READ_BUFFER_SIZE / 4 {
+            self.buffer.reserve(READ_BUFFER_SIZE);
+        }
+        let read = self.stream.read_buf(&mut self.buffer).await?;
+        self.bytes_read += read;
+        Ok(read)
+    }
+}
+
+// requests without side effects, which can be sent again
+fn is_safe(method: &Method) -> bool {
+    matches!(
+        method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+// whether the error means that the server closed the connection
+fn is_closed(e: &anyhow::Error) -> bool {
+    if let Some(ClientError::ConnectionClosed) = e.downcast_ref() {
+        return true;
+    }
+    matches!(
+        e.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+        )
+    )
+}
+
+// finds the end of the response head (including the empty line)
+fn find_head_end(buf: &[u8], from: usize) -> Option<usize> {
+    (from..buf.len()).find_map(|i| {
+        if buf[i..].starts_with(b"\r\n\r\n") {
+            Some(i + 4)
+        } else if buf[i..].starts_with(b"\n\n") {
+            Some(i + 2)
+        } else {
+            None
+        }
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+/// Incrementally decodes a body sent with `Transfer-Encoding: chunked`
+pub struct ChunkedDecoder {
+    state: ChunkState,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        ChunkedDecoder {
+            state: ChunkState::Size,
+        }
+    }
+
+    /// Consumes as much of `buf` as possible and appends the decoded data to `body`.
+    /// Returns `true` once the last chunk (and trailers) have been read.
+    pub fn decode(&mut self, buf: &mut BytesMut, body: &mut Vec<u8>) -> Result<bool, ClientError> {
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let line = match take_line(buf)? {
+                        Some(line) => line,
+                        None => return Ok(false),
+                    };
+
+                    // chunk extensions (`;name=value`) are ignored
+                    let size = std::str::from_utf8(&line)
+                        .ok()
+                        .and_then(|line| line.split(';').next())
+                        .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                        .ok_or(ClientError::Chunked)?;
+
+                    self.state = match size {
+                        0 => ChunkState::Trailers,
+                        size => ChunkState::Data(size),
+                    };
+                }
+                ChunkState::Data(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(false);
+                    }
+
+                    let n = remaining.min(buf.len());
+                    body.extend_from_slice(&buf[..n]);
+                    buf.advance(n);
+
+                    self.state = match remaining - n {
+                        0 => ChunkState::DataEnd,
+                        remaining => ChunkState::Data(remaining),
+                    };
+                }
+                ChunkState::DataEnd => match take_line(buf)? {
+                    Some(line) if line.is_empty() => self.state = ChunkState::Size,
+                    Some(_) => return Err(ClientError::Chunked),
+                    None => return Ok(false),
+                },
+                ChunkState::Trailers => match take_line(buf)? {
+                    Some(line) if line.is_empty() => self.state = ChunkState::Done,
+                    Some(_) => {}
+                    None => return Ok(false),
+                },
+                ChunkState::Done => return Ok(true),
+            }
+        }
+    }
+}
+
+// takes a single line without its line ending, if it is complete
+fn take_line(buf: &mut BytesMut) -> Result<Option<Bytes>, ClientError> {
+    let end = match buf.iter().position(|b| b == &b'\n') {
+        Some(end) => end,
+        None if buf.len() > MAX_HEAD_SIZE => return Err(ClientError::Chunked),
+        None => return Ok(None),
+    };
+
+    let mut line = buf.split_to(end + 1).freeze();
+    line.truncate(end);
+    if line.ends_with(b"\r") {
+        line.truncate(end - 1);
+    }
+    Ok(Some(line))
+}
+
+/// A HTTP/1.1 client which keeps a pool of keep-alive connections per host
+#[derive(Clone)]
+pub struct HttpClient {
+    pool: Arc<Mutex<HashMap<String, Vec<Connection>>>>,
+    timeout: Duration,
+    max_redirects: usize,
+    max_idle_per_host: usize,
+    max_body_size: usize,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        HttpClient {
+            pool: Arc::new(Mutex::new(HashMap::new())),
+            timeout: Duration::from_secs(30),
+            max_redirects: 10,
+            max_idle_per_host: 8,
+            max_body_size: MAX_BODY_SIZE,
+        }
+    }
+
+    /// Timeout for a single request, including connecting and reading the body
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Maximum number of redirects to follow, 0 disables following redirects
+    pub fn max_redirects(&mut self, max_redirects: usize) -> &mut Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Maximum number of idle connections kept open for a single host
+    pub fn max_idle_per_host(&mut self, max_idle_per_host: usize) -> &mut Self {
+        self.max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    /// Larger response bodies are rejected, defaults to 64 MiB
+    pub fn max_body_size(&mut self, size: usize) -> &mut Self {
+        self.max_body_size = size;
+        self
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response> {
+        let mut request = Request::new();
+        request.method = Some(Method::GET);
+        self.send(url, request).await
+    }
+
+    pub async fn post(&self, url: &str, content_type: &str, body: &[u8]) -> Result<Response> {
+        let mut request = Request::new();
+        request.method = Some(Method::POST);
+        request.set_header("Content-Type", content_type);
+        request.body = body.to_vec();
+        self.send(url, request).await
+    }
+
+    /// Sends a request to `url`, following redirects.
+    /// The path and `Host` header of the request are set from the url.
+    pub async fn send(&self, url: &str, mut request: Request) -> Result<Response> {
+        let mut url = Url::parse(url)?;
+        let mut redirects = 0;
+
+        loop {
+            request.path = Some(url.path.clone());
+            request.headers.remove("Host");
+            request.set_header("Host", &url.authority());
+
+            let response = match time::timeout(self.timeout, self.execute(&url, &request)).await {
+                Ok(response) => response?,
+                Err(_) => return Err(ClientError::Timeout.into()),
+            };
+
+            let location = match response.status_code.as_u16() {
+                301 | 302 | 303 | 307 | 308 => response.headers.get_str("Location").ok(),
+                _ => None,
+            };
+
+            let location = match location {
+                Some(location) if self.max_redirects > 0 => location,
+                _ => return Ok(response),
+            };
+
+            redirects += 1;
+            if redirects > self.max_redirects {
+                return Err(ClientError::TooManyRedirects.into());
+            }
+
+            // like browsers, we only keep the method and body for 307 and 308
+            // (and for 301/302 requests which weren't POSTs)
+            let status = response.status_code;
+            let method = request.method.clone().unwrap_or(Method::GET);
+            if status == StatusCode::SeeOther && method != Method::HEAD
+                || matches!(status.as_u16(), 301 | 302) && method == Method::POST
+            {
+                request.method = Some(Method::GET);
+                request.body.clear();
+                request.headers.remove("Content-Type");
+                request.headers.remove("Content-Length");
+            }
+
+            let next = url.join(location.trim())?;
+            if next.host != url.host || next.port != url.port {
+                for header in CREDENTIAL_HEADERS {
+                    request.headers.remove(header);
+                }
+            }
+            url = next;
+        }
+    }
+
+    // sends a single request over a pooled (or new) connection
+    async fn execute(&self, url: &Url, request: &Request) -> Result<Response> {
+        let method = request.method.clone().unwrap_or(Method::GET);
+        let key = format!("{}:{}", url.host, url.port);
+
+        if let Some(mut connection) = self.checkout(&key) {
+            let read = connection.bytes_read;
+            match HttpClient::roundtrip(&mut connection, request, &method).await {
+                Ok((response, keep_alive)) => {
+                    if keep_alive {
+                        self.checkin(key, connection);
+                    }
+                    return Ok(response);
+                }
+                // the server might have closed the idle connection in the meantime, safe
+                // requests are retried once on a new connection if nothing was received
+                Err(e) if is_safe(&method) && connection.bytes_read == read && is_closed(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut connection = Connection::connect(&url.host, url.port).await?;
+        connection.max_body_size(self.max_body_size);
+        let (response, keep_alive) =
+            HttpClient::roundtrip(&mut connection, request, &method).await?;
+
+        if keep_alive {
+            self.checkin(key, connection);
+        }
+
+        Ok(response)
+    }
+
+    async fn roundtrip(
+        connection: &mut Connection,
+        request: &Request,
+        method: &Method,
+    ) -> Result<(Response, bool)> {
+        connection.send(request).await?;
+        connection.read_response(method).await
+    }
+
+    fn checkout(&self, key: &str) -> Option<Connection> {
+        self.pool.lock().get_mut(key).and_then(|idle| idle.pop())
+    }
+
+    fn checkin(&self, key: String, connection: Connection) {
+        // unread bytes mean the server sent something we didn't expect
+        if !connection.buffer.is_empty() {
+            return;
+        }
+
+        let mut pool = self.pool.lock();
+        let idle = pool.entry(key).or_default();
+        if idle.len() < self.max_idle_per_host {
+            idle.push(connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parse_urls() {
+        let url = Url::parse("http://localhost:8080/test?a=b#c").expect("parsing url");
+        assert_eq!(url.host, "localhost");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/test?a=b");
+
+        let url = Url::parse("http://[::1]").expect("parsing url");
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.authority(), "[::1]");
+        assert_eq!(url.path, "/");
+
+        Url::parse("https://localhost").expect_err("parsing url");
+        Url::parse("localhost").expect_err("parsing url");
+    }
+
+    #[test]
+    fn join_redirect_locations() {
+        let url = Url::parse("http://localhost/a/b").unwrap();
+        assert_eq!(url.join("c").unwrap().path, "/a/c");
+        assert_eq!(url.join("/c").unwrap().path, "/c");
+        assert_eq!(url.join("http://example.com/").unwrap().host, "example.com");
+    }
+
+    #[test]
+    fn decode_chunked_body() {
+        let mut decoder = ChunkedDecoder::new();
+        let mut body = vec![];
+
+        let mut buf = BytesMut::from(&b"4\r\nWiki\r\n5;ext=1\r\npe"[..]);
+        assert!(!decoder.decode(&mut buf, &mut body).unwrap());
+
+        buf.extend_from_slice(b"dia\r\n0\r\nx-trailer: 1\r\n\r\nrest");
+        assert!(decoder.decode(&mut buf, &mut body).unwrap());
+        assert_eq!(body, b"Wikipedia");
+        assert_eq!(&buf[..], b"rest");
+    }
+
+    #[test]
+    fn do_not_accept_invalid_chunks() {
+        let mut buf = BytesMut::from(&b"zz\r\n"[..]);
+        ChunkedDecoder::new()
+            .decode(&mut buf, &mut vec![])
+            .expect_err("decoding chunk");
+    }
+
+    #[tokio::test]
+    async fn reuse_pooled_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // only accepts a single connection, so the second request has to reuse it
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            for body in [&b"first"[..], b"second"] {
+                while find_head_end(&buf, 0).is_none() {
+                    socket.read_buf(&mut buf).await.unwrap();
+                }
+                buf.clear();
+
+                let mut resp = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+                    .into_bytes();
+                resp.extend_from_slice(body);
+                socket.write_all(&resp).await.unwrap();
+            }
+        });
+
+        let client = HttpClient::new();
+        let url = format!("http://127.0.0.1:{}/", port);
+
+        let response = client.get(&url).await.expect("first request");
+        assert_eq!(response.body, b"first");
+
+        let response = client.get(&url).await.expect("second request");
+        assert_eq!(response.body, b"second");
+    }
+
+    #[tokio::test]
+    async fn reject_large_bodies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            while find_head_end(&buf, 0).is_none() {
+                socket.read_buf(&mut buf).await.unwrap();
+            }
+            let resp = b"HTTP/1.1 200 OK\r\nContent-Length: 99999999999\r\n\r\n";
+            socket.write_all(resp).await.unwrap();
+        });
+
+        let mut client = HttpClient::new();
+        client.max_body_size(1024);
+        let err = client
+            .get(&format!("http://127.0.0.1:{}/", port))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::BodyTooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn drop_credentials_on_redirects_to_other_hosts() {
+        // answers a single request with `response` and returns the request head
+        async fn serve_once(listener: TcpListener, response: String) -> String {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            while find_head_end(&buf, 0).is_none() {
+                socket.read_buf(&mut buf).await.unwrap();
+            }
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(buf.to_vec()).unwrap()
+        }
+
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_port = other.local_addr().unwrap().port();
+        let target = tokio::spawn(serve_once(
+            other,
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string(),
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let redirect = format!(
+            "HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:{}/\r\nContent-Length: 0\r\n\r\n",
+            other_port
+        );
+        let origin = tokio::spawn(serve_once(listener, redirect));
+
+        let mut request = Request::new();
+        request.method = Some(Method::GET);
+        request.set_header("Authorization", "Bearer secret");
+        request.set_header("Cookie", "session=1");
+        request.set_header("Accept", "text/plain");
+        HttpClient::new()
+            .send(&format!("http://127.0.0.1:{}/", port), request)
+            .await
+            .unwrap();
+
+        let first = origin.await.unwrap();
+        assert!(first.contains("Authorization: Bearer secret"));
+
+        // the redirect points to another port, so another origin
+        let second = target.await.unwrap();
+        assert!(!second.contains("Authorization"));
+        assert!(!second.contains("Cookie"));
+        assert!(second.contains("Accept: text/plain"));
+    }
+
+    #[tokio::test]
+    async fn retry_only_when_idle_connection_was_closed() {
+        const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        const TOO_LARGE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 99999\r\n\r\n";
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            // every connection answers its requests and is closed afterwards
+            for responses in [vec![OK, TOO_LARGE], vec![OK], vec![OK]] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                for response in responses {
+                    let mut buf = BytesMut::new();
+                    while find_head_end(&buf, 0).is_none() {
+                        socket.read_buf(&mut buf).await.unwrap();
+                    }
+                    socket.write_all(response).await.unwrap();
+                }
+            }
+            let timeout = Duration::from_millis(100);
+            assert!(time::timeout(timeout, listener.accept()).await.is_err());
+        });
+
+        let mut client = HttpClient::new();
+        client.max_body_size(1024);
+        let url = format!("http://127.0.0.1:{}/", port);
+        client.get(&url).await.unwrap();
+
+        // the server answered, so the request isn't sent again
+        let err = client.get(&url).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::BodyTooLarge)
+        ));
+
+        // the pooled connection was closed by the server, so a new one is used
+        client.get(&url).await.unwrap();
+        client.get(&url).await.unwrap();
+        server.await.unwrap();
+    }
+}
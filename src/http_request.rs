@@ -1,4 +1,4 @@
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes};
 use std::{
     collections::{btree_map, BTreeMap},
     convert::{TryFrom, TryInto},
@@ -39,23 +39,73 @@ pub enum HeaderError {
     #[error("header value is not a valid string")]
     InvalidString,
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Headers {
     headers: BTreeMap<String, Vec<u8>>,
 }
 
 impl Headers {
+    pub fn new() -> Self {
+        Headers {
+            headers: BTreeMap::new(),
+        }
+    }
+
     pub fn iter(&self) -> btree_map::Iter<String, Vec<u8>> {
         self.headers.iter()
     }
 
+    /// Sets a header, replacing an existing one regardless of the case of its name
+    pub fn insert(&mut self, name: &str, value: &[u8]) -> Option<Vec<u8>> {
+        let previous = self.remove(name);
+        self.headers.insert(name.to_string(), value.to_vec());
+        previous
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Vec<u8>> {
+        let key = self.find_key(name)?;
+        self.headers.remove(&key)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_ok()
+    }
+
     pub fn get_str(&self, name: &str) -> Result<String, HeaderError> {
         let header = self.get(name)?;
         String::from_utf8(header.to_vec()).map_err(|_| HeaderError::InvalidString)
     }
 
+    // header names are case-insensitive, so we fall back to a slower search
+    // if the name doesn't match exactly
     pub fn get(&self, name: &str) -> Result<&Vec<u8>, HeaderError> {
-        self.headers.get(name).ok_or(HeaderError::NotFound)
+        if let Some(value) = self.headers.get(name) {
+            return Ok(value);
+        }
+
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+            .ok_or(HeaderError::NotFound)
+    }
+
+    /// Checks if a comma separated header (like `Connection: keep-alive, Upgrade`)
+    /// contains a token, ignoring case
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        match self.get(name) {
+            Ok(value) => value
+                .split(|b| b == &b',')
+                .any(|value| value.trim_ascii().eq_ignore_ascii_case(token.as_bytes())),
+            Err(_) => false,
+        }
+    }
+
+    fn find_key(&self, name: &str) -> Option<String> {
+        self.headers
+            .keys()
+            .find(|key| key.eq_ignore_ascii_case(name))
+            .cloned()
     }
 }
 
@@ -123,13 +173,48 @@ impl Request {
             method: None,
             path: None,
             version: None,
-            headers: Headers {
-                headers: BTreeMap::new(),
-            },
+            headers: Headers::new(),
             body: vec![],
         }
     }
 
+    /// Sets a header and returns the previous value
+    pub fn set_header(&mut self, key: &str, value: &str) -> Option<Vec<u8>> {
+        self.headers.insert(key, value.as_bytes())
+    }
+
+    // Serializes the request so it can be sent by a client
+    pub fn build(&self) -> Vec<u8> {
+        let method = self.method.clone().unwrap_or(Method::GET);
+        let path = self.path.clone().unwrap_or_else(|| "/".to_string());
+
+        // request line
+        let mut request = method.to_string().into_bytes();
+        request.put_slice(b" ");
+        request.put_slice(path.as_bytes());
+        request.put_slice(b" HTTP/1.");
+        request.put_slice(self.version.unwrap_or(1).to_string().as_bytes());
+        request.put_slice(b"\r\n");
+
+        // add headers
+        let mut headers = self.headers.clone();
+        if !self.body.is_empty() || matches!(method, Method::POST | Method::PUT | Method::PATCH) {
+            headers.insert("Content-Length", self.body.len().to_string().as_bytes());
+        }
+
+        for (key, val) in headers.iter() {
+            request.put_slice(key.as_bytes());
+            request.put_slice(b": ");
+            request.put_slice(val);
+            request.put_slice(b"\r\n");
+        }
+        request.put_slice(b"\r\n");
+
+        // add body
+        request.put_slice(&self.body);
+        request
+    }
+
     pub fn parse(&mut self, buf: Bytes) -> Result<(), RequestError> {
         let mut bytes = buf;
 
@@ -290,6 +375,35 @@ mod tests {
         assert_eq!(request.path, Some(String::from("/test")));
     }
 
+    #[test]
+    fn build_basic_request() {
+        let mut request = Request::new();
+        request.method = Some(Method::POST);
+        request.path = Some("/test".to_string());
+        request.set_header("Host", "localhost");
+        request.body = b"hi".to_vec();
+
+        assert_eq!(
+            request.build(),
+            b"POST /test HTTP/1.1\r\nContent-Length: 2\r\nHost: localhost\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn replace_headers_ignoring_case() {
+        let mut request = Request::new();
+        request
+            .parse(Bytes::from_static(
+                b"POST /test HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi",
+            ))
+            .unwrap();
+
+        let previous = request.set_header("content-length", "3");
+        assert_eq!(previous, Some(b"2".to_vec()));
+        assert_eq!(request.headers.iter().count(), 1);
+        assert_eq!(request.headers.get_str("Content-Length").unwrap(), "3");
+    }
+
     #[test]
     fn accept_only_newline() {
         let mut request = Request::new();
@@ -1,7 +1,79 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use httpstatus::StatusCode;
 use std::collections::BTreeMap;
 
+use crate::http_request::{Headers, Request, RequestError};
+
+/// A parsed response, as received by a client
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// The response version, such as `HTTP/1.1`.
+    pub version: Option<u8>,
+    /// The response status, such as `200 OK`.
+    pub status_code: StatusCode,
+    /// The response headers.
+    pub headers: Headers,
+    /// The response body.
+    pub body: Vec<u8>,
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Response {
+    pub fn new() -> Self {
+        Response {
+            version: None,
+            status_code: StatusCode::Ok,
+            headers: Headers::new(),
+            body: vec![],
+        }
+    }
+
+    pub fn parse(&mut self, buf: Bytes) -> Result<(), RequestError> {
+        let mut bytes = buf;
+
+        self.version = Some(Request::parse_version(&mut bytes)?);
+        Request::parse_space(&mut bytes)?;
+        self.status_code = Response::parse_status(&mut bytes)?;
+
+        Request::parse_new_line(&mut bytes)?;
+        Request::parse_headers(&mut bytes, &mut self.headers)?;
+        Request::parse_new_line(&mut bytes)?;
+
+        if bytes.remaining() != 0 {
+            self.body = bytes.to_vec();
+        }
+
+        Ok(())
+    }
+
+    // parses the status code and skips the reason phrase, e.g `404 Not Found`
+    pub fn parse_status(bytes: &mut Bytes) -> Result<StatusCode, RequestError> {
+        if bytes.remaining() < 3 || !bytes[..3].iter().all(u8::is_ascii_digit) {
+            return Err(RequestError::Status);
+        }
+
+        let code = std::str::from_utf8(&bytes[..3])
+            .ok()
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or(RequestError::Status)?;
+        bytes.advance(3);
+
+        // the reason phrase is optional and only meant for humans
+        let end = bytes
+            .iter()
+            .position(|b| b == &b'\r' || b == &b'\n')
+            .ok_or(RequestError::NewLine)?;
+        bytes.advance(end);
+
+        Ok(StatusCode::from(code))
+    }
+}
+
 #[derive(Clone)]
 pub struct ResponseBuilder {
     status_code: StatusCode,
@@ -59,7 +131,38 @@ impl ResponseBuilder {
         }
     }
 
+    /// Looks up a header which was set with `set_header`, ignoring the case of the name
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All headers which were set with `set_header`
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    pub fn status(&self) -> &StatusCode {
+        &self.status_code
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
     pub fn build(&self) -> Vec<u8> {
+        let mut response = self.build_head(Some(self.body.len()));
+
+        // add body
+        response.put(self.body.clone());
+        response
+    }
+
+    /// Builds the status line and headers, without a `Content-Length` for responses
+    /// which are streamed until the connection is closed
+    pub fn build_head(&self, content_length: Option<usize>) -> Vec<u8> {
         // http version
         let mut response = b"HTTP/1.1 ".to_vec();
 
@@ -67,34 +170,34 @@ impl ResponseBuilder {
         response.put_slice(self.status_code.as_u16().to_string().as_bytes());
         response.put_slice(b" ");
         response.put(self.status_code.reason_phrase().as_bytes());
-
-        // parse body
-        let body = self.body.clone();
-        let content_length = self.body.len();
-        let content_type = if !self.content_type.is_empty() {
-            self.content_type.clone()
-        } else {
-            "text/plain".to_string()
-        };
         response.put_slice(b"\r\n");
 
         // add headers
-        let mut headers = self.headers.clone();
-        headers.insert("Content-Type".to_string(), content_type);
-        headers.insert("Content-Length".to_string(), content_length.to_string());
-
-        for (key, val) in &headers {
+        for (key, val) in &self.header_list(content_length) {
             response.put_slice(key.as_bytes());
             response.put_slice(b": ");
             response.put_slice(val.as_bytes());
             response.put_slice(b"\r\n");
         }
         response.put_slice(b"\r\n");
-
-        // add body
-        response.put(body);
         response
     }
+
+    /// All headers that are sent, including `Content-Type` and `Content-Length`
+    pub(crate) fn header_list(&self, content_length: Option<usize>) -> BTreeMap<String, String> {
+        let content_type = if !self.content_type.is_empty() {
+            self.content_type.clone()
+        } else {
+            "text/plain".to_string()
+        };
+
+        let mut headers = self.headers.clone();
+        headers.insert("Content-Type".to_string(), content_type);
+        if let Some(content_length) = content_length {
+            headers.insert("Content-Length".to_string(), content_length.to_string());
+        }
+        headers
+    }
 }
 
 #[cfg(test)]
@@ -112,6 +215,29 @@ mod tests {
         )
     }
 
+    #[test]
+    fn parse_basic_response() {
+        let mut response = Response::new();
+        response
+            .parse(Bytes::from_static(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\nx-test: a\r\n\r\nhi",
+            ))
+            .expect("parsing response");
+
+        assert_eq!(response.version, Some(1));
+        assert_eq!(response.status_code, StatusCode::NotFound);
+        assert_eq!(response.headers.get_str("content-length").unwrap(), "2");
+        assert_eq!(response.body, b"hi");
+    }
+
+    #[test]
+    fn do_not_accept_invalid_status() {
+        let mut response = Response::new();
+        response
+            .parse(Bytes::from_static(b"HTTP/1.1 2x0 OK\r\n\r\n"))
+            .expect_err("parsing response");
+    }
+
     #[test]
     fn empty_response() {
         let response = ResponseBuilder::new();
@@ -2,42 +2,103 @@
 #![feature(fn_traits)]
 #![feature(associated_type_bounds)]
 #![feature(async_closure)]
+#![feature(unix_socket_ancillary_data)]
 
 use anyhow::Result;
 use bytes::{Bytes, BytesMut};
+use futures::future::{self, BoxFuture, Either};
 use parking_lot::Mutex;
 use router::Route;
-// helpers for zero-copy
-use socket2::{Domain, Socket, Type};
+use std::future::Future;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use std::vec;
 use thiserror::Error;
-use tokio::runtime;
+use tokio::sync::{watch, OwnedSemaphorePermit};
+use tokio::{runtime, time};
 
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
 use tokio::io::{AsyncReadExt, AsyncWriteExt}; // this implements async operations on buffers
-use tokio::net::{TcpListener, TcpStream};
-
-use crate::router::{middleware_matches_request, MiddlewareContext, RequestPath};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio_rustls::TlsAcceptor;
+
+use crate::connections::{AcceptBackoff, ConnectionGuard, Connections};
+use crate::listener::{Listener, ListenerBuilder};
+use crate::proxy_protocol::ProxiedStream;
+use crate::router::{middleware_matches_request, MiddlewareContext, MiddlewareCtx, RequestPath};
+use crate::tls::TlsConfig;
+use crate::transport::{BoxedTransport, ConnectionInfo, Transport};
 pub use httpstatus::{StatusClass, StatusCode};
 
+mod connections;
+pub mod http2;
+pub mod http_client;
 pub mod http_request;
 pub mod http_response;
+pub mod listener;
 mod macros;
+pub mod proxy_protocol;
+#[cfg(unix)]
+pub mod restart;
 pub mod router;
+pub mod sse;
+pub mod tls;
 pub mod tokens;
+pub mod transport;
+#[cfg(unix)]
+pub mod unix;
+pub mod upgrade;
 pub mod websocket;
 
 const REQUEST_BUFFER_SIZE: usize = 30000;
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+// how long closing the connections after the drain timeout may take
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+// clients which don't finish the handshake in time are dropped, so they don't hold a connection slot
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Error, Debug)]
-pub enum ServerError {}
+pub enum ServerError {
+    /// The middleware already prepared an error response, which is sent instead of a 500
+    #[error("request rejected")]
+    Rejected,
+    #[error("hot restart isn't supported with a runtime per thread")]
+    HotRestartPerThread,
+    #[error("a runtime per thread needs SO_REUSEPORT, which is only available on unix")]
+    ThreadPerCoreUnsupported,
+}
 
 pub struct HTTPServer {
     routes_mut: Vec<Route>,
     routes: Arc<Vec<Route>>,
     loglevel: LogLevel,
+    http2: bool,
+    runtime: RuntimeMode,
+    worker_threads: Option<usize>,
+    thread_name: Option<String>,
+    connections: Arc<Connections>,
+    reject_when_full: bool,
+    drain_timeout: Duration,
+    #[cfg(unix)]
+    hot_restart: Option<PathBuf>,
+}
+
+/// How the tokio runtime of the `*_blocking` functions is set up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeMode {
+    /// One runtime with a pool of worker threads
+    MultiThread,
+    /// Everything runs on the calling thread
+    CurrentThread,
+    /// A single threaded runtime per thread, each accepting on its own `SO_REUSEPORT` listeners,
+    /// so the kernel balances the connections between the threads (unix only, elsewhere listening fails)
+    ThreadPerCore,
 }
 
 #[repr(usize)]
@@ -64,6 +125,15 @@ impl<'a> HTTPServer {
             routes: Arc::new(vec![]),
             routes_mut: Vec::with_capacity(100),
             loglevel: LogLevel::Off,
+            http2: false,
+            runtime: RuntimeMode::MultiThread,
+            worker_threads: None,
+            thread_name: None,
+            connections: Connections::new(),
+            reject_when_full: false,
+            drain_timeout: Duration::from_secs(30),
+            #[cfg(unix)]
+            hot_restart: None,
         }
     }
 
@@ -73,8 +143,48 @@ impl<'a> HTTPServer {
 
     // start listening on a new socket/port
     pub fn listen_blocking(&mut self, address: SocketAddr) -> Result<()> {
-        let rt = runtime::Runtime::new()?;
-        rt.block_on(self.listen(address))
+        self.listen_with_blocking(ListenerBuilder::new().bind(address))
+    }
+
+    // start listening for https connections on a new socket/port
+    pub fn listen_tls_blocking(&mut self, address: SocketAddr, tls: TlsConfig) -> Result<()> {
+        self.listen_with_blocking(ListenerBuilder::new().bind(address).tls(tls))
+    }
+
+    // start listening on all addresses of the builder, using its socket options
+    pub fn listen_with_blocking(&mut self, listener: &ListenerBuilder) -> Result<()> {
+        match self.runtime {
+            RuntimeMode::ThreadPerCore => self.listen_per_thread(listener),
+            _ => self.build_runtime()?.block_on(self.serve(listener)),
+        }
+    }
+
+    // start listening on a unix domain socket, `mode` sets the permissions of the socket file
+    #[cfg(unix)]
+    pub fn listen_unix_blocking(
+        &mut self,
+        path: impl AsRef<Path>,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        self.build_runtime()?.block_on(self.serve_unix(path, mode))
+    }
+
+    // the runtime of the blocking functions, `ThreadPerCore` falls back to a multi-thread runtime for unix sockets
+    fn build_runtime(&self) -> std::io::Result<runtime::Runtime> {
+        let mut builder = match self.runtime {
+            RuntimeMode::CurrentThread => runtime::Builder::new_current_thread(),
+            RuntimeMode::MultiThread | RuntimeMode::ThreadPerCore => {
+                let mut builder = runtime::Builder::new_multi_thread();
+                if let Some(threads) = self.worker_threads {
+                    builder.worker_threads(threads);
+                }
+                builder
+            }
+        };
+        if let Some(name) = &self.thread_name {
+            builder.thread_name(name);
+        }
+        builder.enable_all().build()
     }
 
     pub fn loglevel(&mut self, loglevel: LogLevel) -> &mut Self {
@@ -82,113 +192,525 @@ impl<'a> HTTPServer {
         self
     }
 
-    async fn listen(&mut self, address: SocketAddr) -> Result<()> {
+    /// Accept HTTP/2 connections, using ALPN for tls connections and either prior knowledge
+    /// or `Upgrade: h2c` otherwise. Disabled by default, since e.g server-sent events
+    /// and websockets need a http/1.1 connection.
+    pub fn http2(&mut self, enabled: bool) -> &mut Self {
+        self.http2 = enabled;
+        self
+    }
+
+    /// How the runtime of the `*_blocking` functions is set up, `serve` runs on the current runtime instead
+    pub fn runtime(&mut self, mode: RuntimeMode) -> &mut Self {
+        self.runtime = mode;
+        self
+    }
+
+    /// Number of worker threads, or of runtimes for `RuntimeMode::ThreadPerCore`, defaults to the number of cpus
+    pub fn worker_threads(&mut self, threads: usize) -> &mut Self {
+        assert!(threads > 0, "at least one worker thread is needed");
+        self.worker_threads = Some(threads);
+        self
+    }
+
+    /// Name of the worker threads, numbered per thread with `RuntimeMode::ThreadPerCore`
+    pub fn thread_name(&mut self, name: &str) -> &mut Self {
+        self.thread_name = Some(name.to_string());
+        self
+    }
+
+    /// Limits how many connections are served at once, further connections wait in the listen backlog
+    pub fn max_connections(&mut self, limit: usize) -> &mut Self {
+        self.connections = Connections::with_limit(limit);
+        self
+    }
+
+    /// Answers connections over the limit with a `503 Service Unavailable` instead of letting them wait
+    pub fn reject_when_full(&mut self, reject: bool) -> &mut Self {
+        self.reject_when_full = reject;
+        self
+    }
+
+    /// Passes the listeners to a new server process instead of closing them on restart.
+    ///
+    /// On startup the server connects to the control socket at `path` and takes over the listeners
+    /// of the running server, which then stops accepting and returns from `serve` once its
+    /// connections are drained. The control socket is only accessible by the same user.
+    #[cfg(unix)]
+    pub fn hot_restart(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.hot_restart = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// How long open connections are waited for after the listeners were handed over, defaults to 30 seconds
+    pub fn drain_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Serves all addresses of the builder on the current tokio runtime, e.g inside of an existing application
+    pub async fn serve(&mut self, builder: &ListenerBuilder) -> Result<()> {
         self.routes = Arc::new(self.routes_mut.clone());
+        let tls = self.tls_acceptor(builder)?;
+
+        // Create and bind a TCP listener for every address, inherited listeners might be unix sockets
+        let (listeners, successor) = self.hot_restart_listeners(builder).await?;
+
+        let accepting = Box::pin(self.accept_all(listeners, builder, tls));
+        match future::select(accepting, successor).await {
+            Either::Left((result, _)) => result,
+            Either::Right((result, accepting)) => {
+                result?;
+                // stop accepting, the successor serves the listeners now
+                drop(accepting);
+                self.drain().await;
+                Ok(())
+            }
+        }
+    }
+
+    // every thread runs its own single threaded runtime and accepts on its own listeners
+    fn listen_per_thread(&mut self, builder: &ListenerBuilder) -> Result<()> {
+        if cfg!(not(unix)) {
+            return Err(ServerError::ThreadPerCoreUnsupported.into());
+        }
+        #[cfg(unix)]
+        if self.hot_restart.is_some() {
+            return Err(ServerError::HotRestartPerThread.into());
+        }
+
+        let threads = match self.worker_threads {
+            Some(threads) => threads,
+            None => thread::available_parallelism()?.get(),
+        };
+        let sockets = builder.bind_per_thread(threads)?;
+
+        self.routes = Arc::new(self.routes_mut.clone());
+        let tls = self.tls_acceptor(builder)?;
+
+        // the other threads stop once one of them failed
+        let (stop, stopped) = watch::channel(());
+        let server = &*self;
+        thread::scope(|scope| {
+            let threads = sockets
+                .into_iter()
+                .enumerate()
+                .map(|(i, sockets)| {
+                    let mut thread = thread::Builder::new();
+                    if let Some(name) = &server.thread_name {
+                        thread = thread.name(format!("{}-{}", name, i));
+                    }
+
+                    let (tls, stop, mut stopped) = (tls.clone(), &stop, stopped.clone());
+                    thread.spawn_scoped(scope, move || -> Result<()> {
+                        let rt = runtime::Builder::new_current_thread()
+                            .enable_all()
+                            .build()?;
+                        let result = rt.block_on(async {
+                            let listeners = builder.build_from(sockets)?;
+                            let accepting = Box::pin(server.accept_all(listeners, builder, tls));
+                            match future::select(accepting, Box::pin(stopped.changed())).await {
+                                Either::Left((result, _)) => result,
+                                Either::Right(_) => Ok(()),
+                            }
+                        });
+
+                        if result.is_err() {
+                            let _ = stop.send(());
+                        }
+                        result
+                    })
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+
+            for thread in threads {
+                thread
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("server thread panicked"))??;
+            }
+            Ok(())
+        })
+    }
 
-        // Create and bind a TCP listener
-        // Protocol is None/0 since tcp is implied by Type::STREAM)
-        let socket = Socket::new(Domain::IPV6, Type::STREAM, None)?;
+    fn tls_acceptor(&self, builder: &ListenerBuilder) -> Result<Option<TlsAcceptor>> {
+        match builder.tls_config() {
+            Some(tls) => Ok(Some(tls.acceptor(self.http2)?)),
+            None => Ok(None),
+        }
+    }
 
-        // Enable processing of both ipv6 and ipv4 packets
-        socket.set_only_v6(false)?;
+    // accepts connections on all listeners, which only stop on errors
+    async fn accept_all(
+        &self,
+        listeners: Vec<Listener>,
+        builder: &ListenerBuilder,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<()> {
+        let accept_loops = listeners.into_iter().map(|listener| {
+            let tls = tls.clone();
+            async move {
+                match listener {
+                    Listener::Tcp(listener) => {
+                        println!("started server on {}", listener.local_addr()?);
+                        self.accept_tcp(listener, builder, tls).await
+                    }
+                    #[cfg(unix)]
+                    Listener::Unix(listener) => {
+                        match listener.local_addr()?.as_pathname() {
+                            Some(path) => println!("started server on {}", path.display()),
+                            None => println!("started server on an unnamed unix socket"),
+                        }
+                        self.accept_unix(listener, builder.uses_proxy_protocol())
+                            .await
+                    }
+                }
+            }
+        });
 
-        // Stop processing requests right when a close/shutdown request is received
-        socket.set_linger(Some(Duration::new(0, 0)))?;
+        future::try_join_all(accept_loops).await?;
+        Ok(())
+    }
 
-        // Set our socket as non-blocking, which will result in
-        // `read`, `write`, `recv` and `send` operations immediately
-        // returning from their calls.
-        // We want this to enable multiple threads to process sockets concurrently.
-        socket.set_nonblocking(true)?;
+    // takes over the listeners of a running server if there is one,
+    // the returned future finishes once they were passed to a successor
+    #[cfg(unix)]
+    async fn hot_restart_listeners(
+        &self,
+        builder: &ListenerBuilder,
+    ) -> Result<(Vec<Listener>, BoxFuture<'static, Result<()>>)> {
+        let path = match &self.hot_restart {
+            Some(path) => path.clone(),
+            None => return Ok((builder.build()?, Box::pin(future::pending()))),
+        };
+
+        let listeners = match restart::take_over(&path).await? {
+            Some(mut takeover) => {
+                let listeners = builder.build_from(std::mem::take(&mut takeover.sockets))?;
+                takeover.ready().await?;
+                println!("took over the listeners of the running server");
+                listeners
+            }
+            None => builder.build()?,
+        };
 
-        // Finally bind the socket to the correct interface/port and start to listen for new connection
-        socket.bind(&address.into())?;
-        socket.listen(128)?;
+        let fds = listeners.iter().map(AsRawFd::as_raw_fd).collect();
+        Ok((listeners, Box::pin(restart::wait_for_successor(path, fds))))
+    }
 
-        // We convert the socket into a tokio::net::TcpListener, since this
-        // includes a handy way to check if a socket is ready (since we use non blocking sockets)
-        // and async functions for reading from/writing to a socket (since we use non-blocking green threads).
-        let listener = TcpListener::from_std(socket.into())?;
+    #[cfg(not(unix))]
+    async fn hot_restart_listeners(
+        &self,
+        builder: &ListenerBuilder,
+    ) -> Result<(Vec<Listener>, BoxFuture<'static, Result<()>>)> {
+        Ok((builder.build()?, Box::pin(future::pending())))
+    }
 
-        println!("started server on {}", address);
+    // waits for the open connections after the listeners were handed over
+    async fn drain(&self) {
+        println!("draining {} connections", self.connections.active());
+        if !self.connections.wait_idle(self.drain_timeout).await {
+            println!(
+                "closing {} connections after the drain timeout",
+                self.connections.active()
+            );
+            // the tasks stop the next time they are polled
+            self.connections.close_all();
+            self.connections.wait_idle(CLOSE_TIMEOUT).await;
+        }
+    }
 
+    async fn accept_tcp(
+        &self,
+        listener: TcpListener,
+        builder: &ListenerBuilder,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<()> {
         // Process incoming requests
         // A separate thread processes all incoming requests
         // -> This thread then creates a new green thread for each of these
         // -> -> This thread then matches the correct middlewares and calls them in the correct order
-        let routes = self.routes.clone();
-        let loglevel = self.loglevel.clone();
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            let connection = self.reserve_connection().await;
+
+            // non-blocking equivalent to socket.accept
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    backoff.error(&e).await;
+                    continue;
+                }
+            };
+            backoff.reset();
+
+            if let Err(e) = builder.configure_stream(&socket) {
+                println!("couldn't configure client socket: {:?}", e);
+            }
+
+            // the tls handshake happens in the new task, so slow clients don't block the listener
+            let connect = HTTPServer::connect(socket, builder.uses_proxy_protocol(), tls.clone());
+            self.spawn_connection(connection, connect);
+        }
+    }
+
+    // reads the PROXY protocol header, which comes before the tls handshake
+    async fn connect<S: Transport + 'static>(
+        socket: S,
+        proxy_protocol: bool,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<BoxedTransport> {
+        if proxy_protocol {
+            let socket = ProxiedStream::accept(socket).await?;
+            return HTTPServer::tls_handshake(socket, tls).await;
+        }
+        HTTPServer::tls_handshake(socket, tls).await
+    }
 
+    async fn tls_handshake<S: Transport + 'static>(
+        socket: S,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<BoxedTransport> {
+        Ok(match tls {
+            Some(tls) => Box::new(time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(socket)).await??),
+            None => Box::new(socket),
+        })
+    }
+
+    /// Serves a unix domain socket on the current tokio runtime
+    #[cfg(unix)]
+    pub async fn serve_unix(&mut self, path: impl AsRef<Path>, mode: Option<u32>) -> Result<()> {
+        let path = path.as_ref();
+        self.routes = Arc::new(self.routes_mut.clone());
+
+        let listener = unix::bind(path, mode)?;
+        println!("started server on {}", path.display());
+        self.accept_unix(listener, false).await
+    }
+
+    #[cfg(unix)]
+    async fn accept_unix(&self, listener: UnixListener, proxy_protocol: bool) -> Result<()> {
+        let mut backoff = AcceptBackoff::default();
         loop {
-            match listener.accept().await {
-                // non-blocking equivalent to socket.accept
-                Ok((socket, addr)) => {
-                    // Spawn a new non-blocking, multithreaded task for each request
-                    // (A task is essentially a green thread)
+            let connection = self.reserve_connection().await;
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    backoff.error(&e).await;
+                    continue;
+                }
+            };
+            backoff.reset();
 
-                    let loglevel = loglevel.clone();
-                    let routes = routes.clone();
+            let connect = HTTPServer::connect(socket, proxy_protocol, None);
+            self.spawn_connection(connection, connect);
+        }
+    }
 
-                    tokio::spawn(async move {
-                        HTTPServer::process_request(routes, socket, addr, loglevel)
-                            .await
-                            .unwrap_or_else(|e| {
-                                println!("{}", e);
-                            })
-                    });
+    // waits for a free slot before accepting, unless connections over the limit are rejected
+    async fn reserve_connection(&self) -> Option<ConnectionGuard> {
+        if self.reject_when_full {
+            None
+        } else {
+            Some(self.connections.reserve().await)
+        }
+    }
+
+    // serves a connection in a new task, `connect` finishes setting it up (e.g the tls handshake)
+    fn spawn_connection<F>(&self, connection: Option<ConnectionGuard>, connect: F)
+    where
+        F: Future<Output = Result<BoxedTransport>> + Send + 'static,
+    {
+        let connection = match connection.or_else(|| self.connections.try_reserve()) {
+            Some(connection) => connection,
+            None => {
+                // too many connections are rejected already, so this one is closed without a response
+                if let Some(permit) = self.connections.try_reject() {
+                    HTTPServer::reject_connection(permit, connect);
                 }
-                Err(e) => println!("couldn't get client: {:?}", e),
+                return;
             }
-        }
+        };
+
+        let routes = self.routes.clone();
+        let loglevel = self.loglevel.clone();
+        let http2 = self.http2;
+
+        // Spawn a new non-blocking, multithreaded task for each request
+        // (A task is essentially a green thread)
+        tokio::spawn(async move {
+            let result = async {
+                let socket = connect.await?;
+                HTTPServer::process_request(routes, socket, loglevel, http2).await
+            };
+
+            // the connection is dropped if it's still open after the drain timeout
+            let result = match future::select(Box::pin(result), Box::pin(connection.closed())).await
+            {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Ok(()),
+            };
+            result.unwrap_or_else(|e| {
+                println!("{}", e);
+            })
+        });
+    }
+
+    // answers with a 503 once the connection limit is reached
+    fn reject_connection<F>(permit: OwnedSemaphorePermit, connect: F)
+    where
+        F: Future<Output = Result<BoxedTransport>> + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let _permit = permit;
+            let reject = async {
+                let mut socket = connect.await?;
+
+                let mut response = http_response::ResponseBuilder::new();
+                response.status_code(StatusCode::ServiceUnavailable);
+                response.set_header("Connection", "close");
+                response.set_header("Retry-After", "1");
+                response.write(b"503 Service Unavailable");
+                socket.write_all(&response.build()).await?;
+                socket.shutdown().await?;
+
+                // read the request, so closing the socket doesn't reset the connection before the client got the response
+                let mut buf = [0; 1024];
+                while socket.read(&mut buf).await? > 0 {}
+                Ok::<_, anyhow::Error>(())
+            };
+
+            // the rejected connections aren't counted, so they must not stay open
+            let _ = time::timeout(REJECT_TIMEOUT, reject).await;
+        });
+    }
+
+    /// Serves a single connection with the routes registered so far, e.g an in-memory stream in tests
+    pub async fn serve_connection(&self, transport: impl Transport + 'static) -> Result<()> {
+        let routes = Arc::new(self.routes_mut.clone());
+        let loglevel = self.loglevel.clone();
+        HTTPServer::process_request(routes, Box::new(transport), loglevel, self.http2).await
     }
 
     // process incoming sockets
     async fn process_request(
         routes: Arc<Vec<Route>>,
-        mut socket: TcpStream, // Equivalent to Socket with some extra async methods
-        _addr: SocketAddr,
+        mut socket: BoxedTransport, // e.g a TcpStream, which might be wrapped in a tls session
         loglevel: LogLevel,
+        http2: bool,
     ) -> Result<()> {
-        let loglevel = loglevel as usize;
+        let connection = ConnectionInfo::from_transport(&*socket);
+
+        if http2 && socket.alpn_protocol() == Some(b"h2") {
+            let handler = HTTPServer::http2_handler(routes, connection, loglevel);
+            return http2::serve_connection(socket, &[], handler).await;
+        }
+
+        let loglevel_num = loglevel.clone() as usize;
 
         // read request
-        // NOTE: readable might give a false positive
-        socket.readable().await?;
         let mut buffer = BytesMut::with_capacity(REQUEST_BUFFER_SIZE);
 
         let request_length = socket.read_buf(&mut buffer).await?;
 
-        if loglevel > 1 {
+        if loglevel_num > 1 {
             println!("got request:\n  length: {}", request_length);
         }
 
+        // h2c with prior knowledge
+        if http2 && buffer.starts_with(http2::PREFACE) {
+            let handler = HTTPServer::http2_handler(routes, connection, loglevel);
+            return http2::serve_connection(socket, &buffer, handler).await;
+        }
+
         // parse requests
         let mut request = http_request::Request::new();
         request.parse(Bytes::from(buffer))?;
 
-        if loglevel > 1 {
+        if loglevel_num > 1 {
             HTTPServer::print_debug_request(&request.clone());
         }
 
+        // tls connections have to use ALPN instead
+        if http2 && !socket.is_tls() {
+            if let Some(settings) = http2::h2c_upgrade_settings(&request) {
+                let handler = HTTPServer::http2_handler(routes, connection, loglevel);
+                return http2::serve_upgraded_connection(socket, request, settings, handler).await;
+            }
+        }
+
+        let ctx =
+            MiddlewareContext::new(request, http_response::ResponseBuilder::default(), socket);
+        let ctx = HTTPServer::run_middlewares(routes, ctx, loglevel_num).await;
+
+        // write response
+        let mut ctx = ctx.lock();
+        if !ctx.is_raw() {
+            let resp = &ctx.response.build();
+            let socket = ctx.socket()?;
+            socket.write_all(resp).await?;
+            // tls connections buffer writes and have to send close_notify,
+            // the client might already be gone at this point
+            socket.flush().await?;
+            let _ = socket.shutdown().await;
+        }
+
+        Ok(())
+    }
+
+    // answers each HTTP/2 stream using the same middlewares
+    fn http2_handler(
+        routes: Arc<Vec<Route>>,
+        connection: ConnectionInfo,
+        loglevel: LogLevel,
+    ) -> impl Fn(http_request::Request) -> BoxFuture<'static, http_response::ResponseBuilder>
+           + Send
+           + Sync
+           + 'static {
+        move |request| {
+            let routes = routes.clone();
+            let connection = connection.clone();
+            let loglevel = loglevel.clone() as usize;
+
+            Box::pin(async move {
+                if loglevel > 1 {
+                    HTTPServer::print_debug_request(&request);
+                }
+
+                let ctx = MiddlewareContext::without_socket(
+                    request,
+                    http_response::ResponseBuilder::default(),
+                    connection,
+                );
+                let ctx = HTTPServer::run_middlewares(routes, ctx, loglevel).await;
+                let response = ctx.lock().response.clone();
+                response
+            })
+        }
+    }
+
+    // calls the matching middlewares in order, prepares a 500 response if one of them fails
+    async fn run_middlewares(
+        routes: Arc<Vec<Route>>,
+        mut ctx: MiddlewareContext,
+        loglevel: usize,
+    ) -> MiddlewareCtx {
         let relevant_middlewares: &mut Vec<(Route, RequestPath)> = &mut vec![];
         for route in routes.iter() {
-            if route.method.is_some() && route.method != request.method {
+            if route.method.is_some() && route.method != ctx.request.method {
                 continue;
             }
 
-            if let Ok(Some(request_path)) = middleware_matches_request(&request, route) {
+            if let Ok(Some(request_path)) = middleware_matches_request(&ctx.request, route) {
                 relevant_middlewares.push((route.clone(), request_path.clone()))
             }
         }
 
-        let mut response = http_response::ResponseBuilder::default();
-        response.set_header("x-powered-by", "webserver-from-scratch");
+        ctx.response
+            .set_header("x-powered-by", "webserver-from-scratch");
 
         // Since the borrow checker doesn't know that the ownership is given up inside the middleware, we sadly need to use a mutes.
         // Theoretically we could use unsafe code instead (with safety guarantees) however I want to avoid that.
-        let ctx = Arc::new(Mutex::new(MiddlewareContext::new(
-            request, response, socket,
-        )));
+        let ctx = Arc::new(Mutex::new(ctx));
 
         let mut err = false;
         for (middleware_route, middleware_path) in relevant_middlewares {
@@ -201,6 +723,13 @@ impl<'a> HTTPServer {
             let fut = handler(ctx.clone());
 
             if let Err(e) = fut.await {
+                if let Some(ServerError::Rejected) = e.downcast_ref::<ServerError>() {
+                    if loglevel > 1 {
+                        println!("{:#}", e);
+                    }
+                    break;
+                }
+
                 err = true;
                 println!("An error occurred on a middleware: {}", e);
                 break;
@@ -218,23 +747,20 @@ impl<'a> HTTPServer {
             ctx.response.status_code(StatusCode::InternalServerError);
         }
 
-        // write response
-        let mut ctx = ctx.lock();
-        if !ctx.is_raw() {
-            let resp = &ctx.response.build();
-            ctx.socket.writable().await?;
-            ctx.socket.write_all(resp).await?;
-        }
-
-        Ok(())
+        ctx
     }
 
     fn print_debug_request(request: &http_request::Request) {
+        // HTTP/2 requests don't have a version
+        let version = match request.version {
+            Some(version) => format!("HTTP/1.{}", version),
+            None => "HTTP/2".to_string(),
+        };
         println!(
-            "  method: {}\n  path: {}\n  version: HTTP/1.{}",
+            "  method: {}\n  path: {}\n  version: {}",
             request.method.clone().unwrap(),
             request.path.clone().unwrap(),
-            request.version.unwrap()
+            version
         );
 
         for (header, value) in request.headers.iter() {
@@ -0,0 +1,553 @@
+use anyhow::Result;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::{io, net::SocketAddr, time::Duration};
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(unix)]
+use std::{
+    env,
+    ops::Range,
+    os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, RawFd},
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::tls::TlsConfig;
+
+#[derive(Error, Debug)]
+pub enum ListenerError {
+    #[error("no address to listen on")]
+    NoAddress,
+    #[error("couldn't listen on {0}: {1}")]
+    Bind(SocketAddr, io::Error),
+    #[cfg(unix)]
+    #[error("file descriptor {0} is not a listening stream socket")]
+    NotAListener(RawFd),
+    #[error("invalid {0} environment variable")]
+    InvalidEnvironment(&'static str),
+}
+
+// the first file descriptor passed by systemd, after stdin, stdout and stderr
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A bound listener, which was either created by the builder or inherited
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Addresses to listen on and the socket options used for them.
+///
+/// ```ignore
+/// let mut listener = ListenerBuilder::new();
+/// listener
+///     .bind("0.0.0.0:8080".parse()?)
+///     .bind("[::]:8080".parse()?)
+///     .only_v6(true)
+///     .nodelay(true);
+/// server.listen_with_blocking(&listener)
+/// ```
+#[derive(Default)]
+pub struct ListenerBuilder {
+    addresses: Vec<SocketAddr>,
+    // pre-bound listeners, e.g from systemd
+    inherited: Vec<Socket>,
+    tls: Option<TlsConfig>,
+    proxy_protocol: bool,
+    backlog: Option<i32>,
+    only_v6: bool,
+    reuse_address: Option<bool>,
+    reuse_port: bool,
+    nodelay: Option<bool>,
+    keepalive: Option<Duration>,
+    linger: Option<Duration>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+impl ListenerBuilder {
+    pub fn new() -> Self {
+        ListenerBuilder {
+            ..Default::default()
+        }
+    }
+
+    /// Adds an address to listen on, ipv4 addresses get an ipv4 socket
+    pub fn bind(&mut self, address: SocketAddr) -> &mut Self {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Serves on a listener which was already bound, e.g by a parent process.
+    /// It is added like any other address, so the socket options apply to its connections.
+    pub fn listener(&mut self, listener: std::net::TcpListener) -> &mut Self {
+        self.inherited.push(Socket::from(listener));
+        self
+    }
+
+    /// Takes ownership of an inherited listening socket, either tcp or unix.
+    /// If it isn't a listening stream socket, the fd is left open.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor which isn't owned by anything else,
+    /// once it was accepted the builder closes it.
+    #[cfg(unix)]
+    pub unsafe fn inherit_fd(&mut self, fd: RawFd) -> Result<&mut Self> {
+        if !is_listener_fd(fd) {
+            return Err(ListenerError::NotAListener(fd).into());
+        }
+
+        self.inherited.push(Socket::from_raw_fd(fd));
+        Ok(self)
+    }
+
+    /// Inherits the listeners passed by systemd socket activation (`LISTEN_FDS` and `LISTEN_PID`)
+    /// and returns how many there were. The variables are removed, so they aren't passed
+    /// on to child processes.
+    #[cfg(unix)]
+    pub fn systemd(&mut self) -> Result<usize> {
+        let fds = listen_fds(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+        )?;
+
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        // systemd passed the fds to this process
+        unsafe { self.inherit_listen_fds(fds) }
+    }
+
+    // Nothing is taken over unless all fds are listeners, so a wrong `LISTEN_FDS`
+    // doesn't close fds which belong to someone else. The caller has to own the fds.
+    #[cfg(unix)]
+    unsafe fn inherit_listen_fds(&mut self, fds: Range<RawFd>) -> Result<usize> {
+        if let Some(fd) = fds.clone().find(|fd| !is_listener_fd(*fd)) {
+            return Err(ListenerError::NotAListener(fd).into());
+        }
+
+        for fd in fds.clone() {
+            let socket = Socket::from_raw_fd(fd);
+            socket.set_cloexec(true)?;
+            self.inherited.push(socket);
+        }
+        Ok(fds.len())
+    }
+
+    /// Serve https on all addresses
+    pub fn tls(&mut self, tls: TlsConfig) -> &mut Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Expect a PROXY protocol (v1 or v2) header at the start of every connection, e.g behind
+    /// a tcp load balancer. The addresses it contains replace the ones of the socket in
+    /// `ctx.connection`, connections without a valid header are closed.
+    pub fn proxy_protocol(&mut self, enabled: bool) -> &mut Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Maximum number of connections waiting to be accepted, defaults to 128
+    pub fn backlog(&mut self, backlog: i32) -> &mut Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// Only accept ipv6 connections on ipv6 addresses. By default they accept
+    /// ipv4 connections as well, so the same port can't be bound with ipv4 and ipv6.
+    pub fn only_v6(&mut self, only_v6: bool) -> &mut Self {
+        self.only_v6 = only_v6;
+        self
+    }
+
+    /// `SO_REUSEADDR`, allows binding while old connections are still in `TIME_WAIT` (the default on unix)
+    pub fn reuse_address(&mut self, reuse: bool) -> &mut Self {
+        self.reuse_address = Some(reuse);
+        self
+    }
+
+    /// `SO_REUSEPORT`, allows several sockets (e.g of multiple processes) to listen on the same port
+    pub fn reuse_port(&mut self, reuse: bool) -> &mut Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// `TCP_NODELAY`, sends small responses right away instead of waiting for more data
+    pub fn nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enables tcp keepalive probes after the connection was idle for `time`
+    pub fn keepalive(&mut self, time: Option<Duration>) -> &mut Self {
+        self.keepalive = time;
+        self
+    }
+
+    /// `SO_LINGER`, not set by default. A timeout of 0 resets connections
+    /// on close, which can cut off responses which weren't sent yet.
+    pub fn linger(&mut self, linger: Option<Duration>) -> &mut Self {
+        self.linger = linger;
+        self
+    }
+
+    /// `SO_RCVBUF`
+    pub fn recv_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// `SO_SNDBUF`
+    pub fn send_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    pub(crate) fn tls_config(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    pub(crate) fn uses_proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// Binds all addresses, has to be called inside of a tokio runtime
+    pub(crate) fn build(&self) -> Result<Vec<Listener>> {
+        if self.addresses.is_empty() && self.inherited.is_empty() {
+            return Err(ListenerError::NoAddress.into());
+        }
+
+        // the builder keeps its own copies of inherited listeners
+        let inherited = self
+            .inherited
+            .iter()
+            .map(|socket| socket.try_clone())
+            .collect::<io::Result<_>>()?;
+        let mut listeners = self.build_from(inherited)?;
+
+        for address in &self.addresses {
+            let listener = self
+                .bind_socket(*address, self.reuse_port)
+                .map_err(|e| ListenerError::Bind(*address, e))?;
+
+            // We convert the socket into a tokio::net::TcpListener, since this
+            // includes a handy way to check if a socket is ready (since we use non blocking sockets)
+            // and async functions for reading from/writing to a socket (since we use non-blocking green threads).
+            listeners.push(Listener::Tcp(TcpListener::from_std(listener.into())?));
+        }
+
+        Ok(listeners)
+    }
+
+    /// Serves the given listening sockets instead of the configured ones
+    pub(crate) fn build_from(&self, sockets: Vec<Socket>) -> Result<Vec<Listener>> {
+        let mut listeners = vec![];
+        for socket in sockets {
+            socket.set_nonblocking(true)?;
+
+            let is_tcp = socket.local_addr()?.as_socket().is_some();
+            listeners.push(match is_tcp {
+                true => Listener::Tcp(TcpListener::from_std(socket.into())?),
+                #[cfg(unix)]
+                false => Listener::Unix(UnixListener::from_std(socket.into())?),
+                #[cfg(not(unix))]
+                false => unreachable!("only tcp listeners can be added"),
+            });
+        }
+        Ok(listeners)
+    }
+
+    /// Binds every address once per thread with `SO_REUSEPORT`, so the kernel balances the
+    /// connections between the threads. Inherited listeners are shared by all threads.
+    pub(crate) fn bind_per_thread(&self, threads: usize) -> Result<Vec<Vec<Socket>>> {
+        if self.addresses.is_empty() && self.inherited.is_empty() {
+            return Err(ListenerError::NoAddress.into());
+        }
+
+        let mut sockets: Vec<Vec<Socket>> = (0..threads).map(|_| vec![]).collect();
+        for socket in &self.inherited {
+            for thread in sockets.iter_mut() {
+                thread.push(socket.try_clone()?);
+            }
+        }
+
+        for address in &self.addresses {
+            let mut address = *address;
+            for thread in sockets.iter_mut() {
+                let socket = self
+                    .bind_socket(address, true)
+                    .map_err(|e| ListenerError::Bind(address, e))?;
+
+                // the other threads use the same port if it was picked by the os
+                address = socket.local_addr()?.as_socket().unwrap_or(address);
+                thread.push(socket);
+            }
+        }
+
+        Ok(sockets)
+    }
+
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn bind_socket(&self, address: SocketAddr, reuse_port: bool) -> io::Result<Socket> {
+        // Protocol is None/0 since tcp is implied by Type::STREAM)
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+
+        if address.is_ipv6() {
+            socket.set_only_v6(self.only_v6)?;
+        }
+        // enabled by default on unix, like `std::net::TcpListener::bind`
+        socket.set_reuse_address(self.reuse_address.unwrap_or(cfg!(unix)))?;
+        #[cfg(unix)]
+        if reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        // accepted connections start with the buffer sizes of the listener,
+        // which also decide the tcp window scaling during the handshake
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        // Set our socket as non-blocking, which will result in
+        // `read`, `write`, `recv` and `send` operations immediately
+        // returning from their calls.
+        // We want this to enable multiple threads to process sockets concurrently.
+        socket.set_nonblocking(true)?;
+
+        // Finally bind the socket to the correct interface/port and start to listen for new connection
+        socket.bind(&address.into())?;
+        socket.listen(self.backlog.unwrap_or(128))?;
+        Ok(socket)
+    }
+
+    /// Applies the options which aren't inherited from the listener on all platforms
+    pub(crate) fn configure_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        if self.linger.is_some() {
+            socket.set_linger(self.linger)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn is_stream_listener(socket: &Socket) -> bool {
+    if !matches!(socket.r#type(), Ok(Type::STREAM)) {
+        return false;
+    }
+
+    #[cfg(target_os = "linux")]
+    if !socket.is_listener().unwrap_or(false) {
+        return false;
+    }
+    true
+}
+
+// checks an fd without taking ownership of it, closed fds fail the checks
+#[cfg(unix)]
+fn is_listener_fd(fd: RawFd) -> bool {
+    if fd < 0 {
+        return false;
+    }
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    is_stream_listener(&SockRef::from(&fd))
+}
+
+// https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+#[cfg(unix)]
+fn listen_fds(pid: Option<&str>, fds: Option<&str>) -> Result<Range<RawFd>> {
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(0..0),
+    };
+
+    let pid: u32 = pid
+        .trim()
+        .parse()
+        .map_err(|_| ListenerError::InvalidEnvironment("LISTEN_PID"))?;
+    // the fds were meant for another process
+    if pid != std::process::id() {
+        return Ok(0..0);
+    }
+
+    let fds: RawFd = fds
+        .trim()
+        .parse()
+        .map_err(|_| ListenerError::InvalidEnvironment("LISTEN_FDS"))?;
+    if fds < 0 {
+        return Err(ListenerError::InvalidEnvironment("LISTEN_FDS").into());
+    }
+    Ok(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::io::IntoRawFd;
+
+    fn tcp(listener: Listener) -> TcpListener {
+        match listener {
+            Listener::Tcp(listener) => listener,
+            _ => panic!("expected a tcp listener"),
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_several_addresses() {
+        let mut builder = ListenerBuilder::new();
+        builder
+            .bind("127.0.0.1:0".parse().unwrap())
+            .bind("[::1]:0".parse().unwrap())
+            .only_v6(true)
+            .backlog(16);
+
+        let listeners: Vec<_> = builder.build().unwrap().into_iter().map(tcp).collect();
+        assert!(listeners[0].local_addr().unwrap().is_ipv4());
+        assert!(listeners[1].local_addr().unwrap().is_ipv6());
+
+        let socket = SockRef::from(&listeners[1]);
+        assert!(socket.only_v6().unwrap());
+
+        assert!(matches!(
+            ListenerBuilder::new()
+                .build()
+                .unwrap_err()
+                .downcast_ref::<ListenerError>(),
+            Some(ListenerError::NoAddress)
+        ));
+    }
+
+    #[tokio::test]
+    async fn configure_accepted_connections() {
+        let mut builder = ListenerBuilder::new();
+        builder
+            .bind("127.0.0.1:0".parse().unwrap())
+            .nodelay(true)
+            .keepalive(Some(Duration::from_secs(30)))
+            .linger(Some(Duration::from_secs(1)));
+
+        let listener = tcp(builder.build().unwrap().remove(0));
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        builder.configure_stream(&stream).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(1)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn share_ports_with_reuse_port() {
+        let mut builder = ListenerBuilder::new();
+        builder
+            .bind("127.0.0.1:0".parse().unwrap())
+            .reuse_port(true);
+        let first = tcp(builder.build().unwrap().remove(0));
+
+        let mut second = ListenerBuilder::new();
+        second.bind(first.local_addr().unwrap()).reuse_port(true);
+        assert!(second.build().is_ok());
+
+        // without SO_REUSEPORT the port is taken
+        let mut third = ListenerBuilder::new();
+        third.bind(first.local_addr().unwrap());
+        assert!(third.build().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_listeners_per_thread() {
+        let mut builder = ListenerBuilder::new();
+        builder.bind("127.0.0.1:0".parse().unwrap());
+        let sockets = builder.bind_per_thread(3).unwrap();
+        assert_eq!(sockets.len(), 3);
+
+        let addresses: Vec<_> = sockets
+            .iter()
+            .map(|sockets| sockets[0].local_addr().unwrap().as_socket().unwrap())
+            .collect();
+        assert_ne!(addresses[0].port(), 0);
+        assert!(addresses.iter().all(|address| *address == addresses[0]));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn inherit_listeners() {
+        let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = inherited.local_addr().unwrap();
+
+        let mut builder = ListenerBuilder::new();
+        unsafe { builder.inherit_fd(inherited.into_raw_fd()) }.unwrap();
+        let listener = tcp(builder.build().unwrap().remove(0));
+        assert_eq!(listener.local_addr().unwrap(), address);
+
+        let _client = TcpStream::connect(address).await.unwrap();
+        listener.accept().await.unwrap();
+
+        // sockets which don't listen are rejected and stay open
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fd = socket.into_raw_fd();
+        assert!(unsafe { builder.inherit_fd(fd) }.is_err());
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+        assert!(socket.local_addr().is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_listen_fds_open_unless_all_are_listeners() {
+        let mut builder = ListenerBuilder::new();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fd = socket.as_raw_fd();
+        assert!(unsafe { builder.inherit_listen_fds(fd..fd + 1) }.is_err());
+        assert!(socket.local_addr().is_ok());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = listener.into_raw_fd();
+        assert_eq!(
+            unsafe { builder.inherit_listen_fds(fd..fd + 1) }.unwrap(),
+            1
+        );
+        assert_eq!(builder.inherited.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parse_listen_fds() {
+        let pid = std::process::id().to_string();
+        assert_eq!(listen_fds(Some(&pid), Some("2")).unwrap(), 3..5);
+        assert_eq!(listen_fds(Some("1"), Some("2")).unwrap(), 0..0);
+        assert_eq!(listen_fds(None, None).unwrap(), 0..0);
+        assert!(listen_fds(Some(&pid), Some("two")).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+//! HAProxy PROXY protocol (v1 and v2), which load balancers send at the start of a
+//! connection to pass on the addresses of the original client and destination.
+//!
+//! See <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>
+
+use anyhow::Result;
+use bytes::{Buf, BytesMut};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str,
+    task::{Context, Poll},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    time,
+};
+
+use crate::transport::{PeerCredentials, Transport};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LENGTH: usize = 16;
+
+/// How long a client has to send the header, so idle connections don't pile up
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ProxyProtocolError {
+    #[error("the connection didn't start with a PROXY protocol header")]
+    MissingHeader,
+    #[error("invalid PROXY protocol header")]
+    InvalidHeader,
+    #[error("unsupported PROXY protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("the connection was closed before the PROXY protocol header was complete")]
+    Incomplete,
+}
+
+/// The addresses passed by the proxy, `None` for connections the proxy opened itself
+/// (e.g health checks) or which aren't tcp
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    fn new(source: SocketAddr, destination: SocketAddr) -> Self {
+        ProxyHeader {
+            source: Some(source),
+            destination: Some(destination),
+        }
+    }
+
+    /// Parses a header at the start of `buf` and returns its length,
+    /// or `None` if more data is needed
+    pub fn parse(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, ProxyProtocolError> {
+        if buf.starts_with(V1_PREFIX) {
+            return parse_v1(buf);
+        }
+        if buf.starts_with(V2_SIGNATURE) {
+            return parse_v2(buf);
+        }
+
+        // wait for enough bytes to tell both versions apart
+        let prefix = &buf[..buf.len().min(V2_SIGNATURE.len())];
+        if V1_PREFIX.starts_with(prefix) || V2_SIGNATURE.starts_with(prefix) {
+            return Ok(None);
+        }
+        Err(ProxyProtocolError::MissingHeader)
+    }
+}
+
+// e.g `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`
+fn parse_v1(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, ProxyProtocolError> {
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LENGTH => return Ok(None),
+        None => return Err(ProxyProtocolError::InvalidHeader),
+    };
+    if end + 2 > V1_MAX_LENGTH {
+        return Err(ProxyProtocolError::InvalidHeader);
+    }
+
+    let line = str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| ProxyProtocolError::InvalidHeader)?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    let header = match parts[..] {
+        // the rest of the line is ignored
+        ["UNKNOWN", ..] => ProxyHeader::default(),
+        [protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let source: IpAddr = parse_field(source)?;
+            let destination: IpAddr = parse_field(destination)?;
+            if source.is_ipv4() != (protocol == "TCP4") || destination.is_ipv4() != source.is_ipv4()
+            {
+                return Err(ProxyProtocolError::InvalidHeader);
+            }
+
+            ProxyHeader::new(
+                SocketAddr::new(source, parse_field(source_port)?),
+                SocketAddr::new(destination, parse_field(destination_port)?),
+            )
+        }
+        _ => return Err(ProxyProtocolError::InvalidHeader),
+    };
+
+    Ok(Some((header, end + 2)))
+}
+
+fn parse_field<T: str::FromStr>(field: &str) -> Result<T, ProxyProtocolError> {
+    field.parse().map_err(|_| ProxyProtocolError::InvalidHeader)
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, ProxyProtocolError> {
+    if buf.len() < V2_HEADER_LENGTH {
+        return Ok(None);
+    }
+
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    let family = buf[13];
+    let length = V2_HEADER_LENGTH + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if version != 2 {
+        return Err(ProxyProtocolError::UnsupportedVersion(version));
+    }
+    if buf.len() < length {
+        return Ok(None);
+    }
+    let addresses = &buf[V2_HEADER_LENGTH..length];
+
+    let header = match (command, family) {
+        // LOCAL, the proxy connected on its own
+        (0x0, _) => ProxyHeader::default(),
+        // PROXY over TCP/UDP and IPv4
+        (0x1, 0x11 | 0x12) if addresses.len() >= 12 => {
+            let ip = |i: usize| {
+                Ipv4Addr::new(
+                    addresses[i],
+                    addresses[i + 1],
+                    addresses[i + 2],
+                    addresses[i + 3],
+                )
+            };
+            let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i + 1]]);
+            ProxyHeader::new(
+                SocketAddr::new(ip(0).into(), port(8)),
+                SocketAddr::new(ip(4).into(), port(10)),
+            )
+        }
+        // PROXY over TCP/UDP and IPv6
+        (0x1, 0x21 | 0x22) if addresses.len() >= 36 => {
+            let ip = |i: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&addresses[i..i + 16]);
+                Ipv6Addr::from(octets)
+            };
+            let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i + 1]]);
+            ProxyHeader::new(
+                SocketAddr::new(ip(0).into(), port(32)),
+                SocketAddr::new(ip(16).into(), port(34)),
+            )
+        }
+        // unix sockets and unspecified protocols have no addresses we can use
+        (0x1, 0x00 | 0x31 | 0x32) => ProxyHeader::default(),
+        _ => return Err(ProxyProtocolError::InvalidHeader),
+    };
+
+    // type-length-value fields after the addresses are skipped
+    Ok(Some((header, length)))
+}
+
+/// A connection which started with a PROXY protocol header.
+///
+/// `peer_addr` and `local_addr` return the addresses of the original connection,
+/// `proxy_addr` the address of the proxy.
+pub struct ProxiedStream<S> {
+    stream: S,
+    buffered: BytesMut,
+    header: ProxyHeader,
+}
+
+impl<S: Transport> ProxiedStream<S> {
+    /// Reads the PROXY protocol header at the start of the connection
+    pub async fn accept(mut stream: S) -> Result<Self> {
+        let mut buffered = BytesMut::with_capacity(V1_MAX_LENGTH);
+
+        let header = time::timeout(HEADER_TIMEOUT, async {
+            loop {
+                if let Some((header, length)) = ProxyHeader::parse(&buffered)? {
+                    // everything after the header belongs to the connection
+                    buffered.advance(length);
+                    return Ok::<_, anyhow::Error>(header);
+                }
+                if stream.read_buf(&mut buffered).await? == 0 {
+                    return Err(ProxyProtocolError::Incomplete.into());
+                }
+            }
+        })
+        .await??;
+
+        Ok(ProxiedStream {
+            stream,
+            buffered,
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &ProxyHeader {
+        &self.header
+    }
+}
+
+impl<S: Transport> Transport for ProxiedStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.header.source.or_else(|| self.stream.peer_addr())
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.header.destination.or_else(|| self.stream.local_addr())
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.stream.peer_credentials()
+    }
+
+    fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.header.source.and(self.stream.peer_addr())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ProxiedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffered.is_empty() {
+            let n = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..n]);
+            self.buffered.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ProxiedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    fn parse(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, ProxyProtocolError> {
+        ProxyHeader::parse(buf)
+    }
+
+    #[test]
+    fn parse_v1_header() {
+        let header = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /";
+        let (parsed, length) = parse(header).unwrap().unwrap();
+        assert_eq!(parsed.source, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(
+            parsed.destination,
+            Some("192.168.0.11:443".parse().unwrap())
+        );
+        assert_eq!(&header[length..], b"GET /");
+
+        let (parsed, _) = parse(b"PROXY TCP6 ::1 ::2 1 2\r\n").unwrap().unwrap();
+        assert_eq!(parsed.source, Some("[::1]:1".parse().unwrap()));
+
+        let (parsed, _) = parse(b"PROXY UNKNOWN ffff::1 ::2 1 2\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed, ProxyHeader::default());
+
+        assert_eq!(parse(b"PROXY TCP4 192.168.0.1"), Ok(None));
+        assert_eq!(parse(b"PRO"), Ok(None));
+        assert_eq!(
+            parse(b"PROXY TCP4 ::1 ::2 1 2\r\n"),
+            Err(ProxyProtocolError::InvalidHeader)
+        );
+        assert_eq!(
+            parse(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 99999\r\n"),
+            Err(ProxyProtocolError::InvalidHeader)
+        );
+        assert_eq!(
+            parse(&[b'P'; 200][..]),
+            Err(ProxyProtocolError::MissingHeader)
+        );
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\n"),
+            Err(ProxyProtocolError::MissingHeader)
+        );
+    }
+
+    #[test]
+    fn parse_v2_header() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY over TCP4, 12 bytes of addresses and a 4 byte TLV
+        header.extend([0x21, 0x11, 0, 16]);
+        header.extend([10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0x01, 0xbb]);
+        header.extend([0x04, 0, 1, 0]);
+        header.extend(b"GET /");
+
+        assert_eq!(parse(&header[..20]), Ok(None));
+        let (parsed, length) = parse(&header).unwrap().unwrap();
+        assert_eq!(parsed.source, Some("10.0.0.1:8080".parse().unwrap()));
+        assert_eq!(parsed.destination, Some("10.0.0.2:443".parse().unwrap()));
+        assert_eq!(&header[length..], b"GET /");
+
+        // LOCAL, e.g a health check
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(parse(&local), Ok(Some((ProxyHeader::default(), 16))));
+
+        let mut version = V2_SIGNATURE.to_vec();
+        version.extend([0x31, 0x11, 0, 0]);
+        assert_eq!(
+            parse(&version),
+            Err(ProxyProtocolError::UnsupportedVersion(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn read_header_before_the_connection() {
+        let (transport, mut client) = duplex(4096);
+        client
+            .write_all(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut stream = ProxiedStream::accept(transport).await.unwrap();
+        assert_eq!(
+            stream.peer_addr(),
+            Some("192.168.0.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            stream.local_addr(),
+            Some("192.168.0.11:443".parse().unwrap())
+        );
+
+        let mut request = vec![0; 18];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, b"GET / HTTP/1.1\r\n\r\n");
+
+        let (transport, mut client) = duplex(4096);
+        client.write_all(b"PROXY TCP4 1.2.3.4").await.unwrap();
+        drop(client);
+        let err = ProxiedStream::accept(transport).await.err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<ProxyProtocolError>(),
+            Some(&ProxyProtocolError::Incomplete)
+        );
+    }
+}
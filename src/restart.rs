@@ -0,0 +1,221 @@
+//! Zero-downtime restarts: a running server passes its listening sockets to its successor
+//! over a unix socket (`SCM_RIGHTS`), stops accepting once the successor is ready and
+//! drains its open connections.
+//!
+//! 1. the new process connects to the control socket and receives the listeners
+//! 2. it answers once it serves them
+//! 3. the old process removes the control socket and closes the connection,
+//!    so the new process can listen for its own successor
+
+use anyhow::Result;
+use socket2::Socket;
+use std::{
+    fs,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        net::{AncillaryData, SocketAncillary, UnixStream},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::task;
+
+use crate::unix;
+
+const READY: u8 = b'R';
+const LISTENERS: u8 = b'L';
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_LISTENERS: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum RestartError {
+    #[error("the running server didn't pass any listeners")]
+    NoListeners,
+    #[error("too many listeners to pass to the successor")]
+    TooManyListeners,
+    #[error("unexpected message on the control socket")]
+    InvalidMessage,
+    #[error("successor runs as user {0}")]
+    ForeignUser(u32),
+}
+
+/// Listeners received from the server which is replaced
+pub(crate) struct Takeover {
+    stream: UnixStream,
+    pub(crate) sockets: Vec<Socket>,
+}
+
+impl Takeover {
+    /// Tells the old server that the listeners are served now, and waits
+    /// until it released the control socket
+    pub(crate) async fn ready(self) -> Result<()> {
+        let mut stream = self.stream;
+        task::spawn_blocking(move || {
+            stream.write_all(&[READY])?;
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest)?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+/// Receives the listeners of the server on the control socket at `path`,
+/// `None` if no server is running
+pub(crate) async fn take_over(path: &Path) -> Result<Option<Takeover>> {
+    let path = path.to_path_buf();
+    task::spawn_blocking(move || {
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.kind() == io::ErrorKind::ConnectionRefused =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+
+        let sockets = recv_fds(&stream)?;
+        for socket in &sockets {
+            socket.set_cloexec(true)?;
+        }
+        if sockets.is_empty() {
+            return Err(RestartError::NoListeners.into());
+        }
+
+        Ok(Some(Takeover { stream, sockets }))
+    })
+    .await?
+}
+
+/// Waits for a successor on the control socket at `path` and passes `fds` to it.
+/// Returns once a successor serves them, failed attempts are ignored.
+pub(crate) async fn wait_for_successor(path: PathBuf, fds: Vec<RawFd>) -> Result<()> {
+    if fds.len() > MAX_LISTENERS {
+        return Err(RestartError::TooManyListeners.into());
+    }
+
+    // only the user running the server may take over its sockets
+    let listener = unix::bind(&path, Some(0o600))?;
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                println!("couldn't get successor: {:?}", e);
+                continue;
+            }
+        };
+
+        // the socket's mode doesn't keep out root
+        if let Err(e) = check_peer(&stream) {
+            println!("hot restart failed: {}", e);
+            continue;
+        }
+        let stream = stream.into_std()?;
+
+        let fds = fds.clone();
+        let handoff = task::spawn_blocking(move || -> Result<UnixStream> {
+            stream.set_nonblocking(false)?;
+            stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+            send_fds(&stream, &fds)?;
+
+            let mut ready = [0];
+            (&stream).read_exact(&mut ready)?;
+            if ready[0] != READY {
+                return Err(RestartError::InvalidMessage.into());
+            }
+            Ok(stream)
+        });
+
+        match handoff.await? {
+            Ok(stream) => {
+                // the successor binds the control socket once the connection is closed
+                drop(listener);
+                let _ = fs::remove_file(&path);
+                drop(stream);
+                return Ok(());
+            }
+            Err(e) => println!("hot restart failed: {}", e),
+        }
+    }
+}
+
+fn check_peer(stream: &tokio::net::UnixStream) -> Result<()> {
+    let uid = stream.peer_cred()?.uid();
+    if uid != unsafe { libc::getuid() } {
+        return Err(RestartError::ForeignUser(uid).into());
+    }
+    Ok(())
+}
+
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> Result<()> {
+    let mut buf = [0; 1024];
+    let mut ancillary = SocketAncillary::new(&mut buf);
+    if !ancillary.add_fds(fds) {
+        return Err(RestartError::TooManyListeners.into());
+    }
+
+    stream.send_vectored_with_ancillary(&[IoSlice::new(&[LISTENERS])], &mut ancillary)?;
+    Ok(())
+}
+
+fn recv_fds(stream: &UnixStream) -> Result<Vec<Socket>> {
+    let mut buf = [0; 1024];
+    let mut ancillary = SocketAncillary::new(&mut buf);
+    let mut message = [0];
+    let length = stream
+        .recv_vectored_with_ancillary(&mut [IoSliceMut::new(&mut message)], &mut ancillary)?;
+
+    let mut sockets = vec![];
+    for data in ancillary.messages().flatten() {
+        if let AncillaryData::ScmRights(fds) = data {
+            // the received fds are new copies owned by this process
+            sockets.extend(fds.map(|fd| unsafe { Socket::from_raw_fd(fd) }));
+        }
+    }
+
+    if length != 1 || message[0] != LISTENERS || ancillary.truncated() {
+        return Err(RestartError::InvalidMessage.into());
+    }
+    Ok(sockets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::{fs::PermissionsExt, io::AsRawFd};
+
+    #[tokio::test]
+    async fn pass_listeners_to_successor() {
+        let path = std::env::temp_dir().join(format!("restart-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(take_over(&path).await.unwrap().is_none());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let old = tokio::spawn(wait_for_successor(path.clone(), vec![listener.as_raw_fd()]));
+
+        // the control socket is bound in the background
+        let takeover = loop {
+            if let Some(takeover) = take_over(&path).await.unwrap() {
+                break takeover;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let received = takeover.sockets[0].local_addr().unwrap();
+        assert_eq!(received.as_socket(), Some(address));
+
+        takeover.ready().await.unwrap();
+        old.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -1,14 +1,14 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures::future::BoxFuture;
 use parking_lot::Mutex;
 use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
-use tokio::net::TcpStream;
 
 // https://stackoverflow.com/questions/27883509/can-you-clone-a-closure
 
 use crate::{
     http_request::{Method, Request},
     http_response::ResponseBuilder,
+    transport::{BoxedTransport, ConnectionInfo},
     HTTPServer,
 };
 
@@ -36,8 +36,11 @@ pub struct MiddlewareContext {
     /// Params
     pub params: BTreeMap<String, RequestPathParams>,
 
-    /// Socket
-    pub socket: TcpStream,
+    /// Addresses of the connection the request was received on
+    pub connection: ConnectionInfo,
+
+    /// Socket, `None` once it has been taken over (e.g by a websocket) or for HTTP/2 streams
+    socket: Option<BoxedTransport>,
 
     /// End the request prematurely
     ended: bool,
@@ -47,9 +50,27 @@ pub struct MiddlewareContext {
 }
 
 impl MiddlewareContext {
-    pub fn new(request: Request, response: ResponseBuilder, socket: TcpStream) -> Self {
+    pub fn new(request: Request, response: ResponseBuilder, socket: BoxedTransport) -> Self {
+        Self {
+            connection: ConnectionInfo::from_transport(&*socket),
+            socket: Some(socket),
+            request,
+            response,
+            ended: false,
+            params: BTreeMap::new(),
+            raw: false,
+        }
+    }
+
+    /// A context for requests which don't own a connection, e.g HTTP/2 streams
+    pub fn without_socket(
+        request: Request,
+        response: ResponseBuilder,
+        connection: ConnectionInfo,
+    ) -> Self {
         Self {
-            socket,
+            connection,
+            socket: None,
             request,
             response,
             ended: false,
@@ -58,6 +79,21 @@ impl MiddlewareContext {
         }
     }
 
+    pub fn socket(&mut self) -> Result<&mut BoxedTransport> {
+        self.socket.as_mut().ok_or_else(|| {
+            anyhow!("socket has already been taken or belongs to a HTTP/2 connection")
+        })
+    }
+
+    // Takes ownership of the socket, no response will be written afterwards
+    pub fn take_socket(&mut self) -> Result<BoxedTransport> {
+        let socket = self.socket.take().ok_or_else(|| {
+            anyhow!("socket has already been taken or belongs to a HTTP/2 connection")
+        })?;
+        self.set_raw(true);
+        Ok(socket)
+    }
+
     pub fn set_raw(&mut self, val: bool) {
         self.raw = val;
     }
@@ -0,0 +1,259 @@
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time,
+};
+
+use crate::{router::MiddlewareContext, StatusCode};
+
+// https://html.spec.whatwg.org/multipage/server-sent-events.html
+
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+const QUEUE_SIZE: usize = 64;
+
+/// A single server-sent event, every field is optional
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Event::default()
+    }
+
+    /// Sent back by the browser as `Last-Event-ID` when it reconnects
+    pub fn id(&mut self, id: &str) -> &mut Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// The event type, browsers dispatch events without one as `message`
+    pub fn event(&mut self, event: &str) -> &mut Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// The payload, which may span multiple lines
+    pub fn data(&mut self, data: &str) -> &mut Self {
+        self.data = Some(data.to_string());
+        self
+    }
+
+    /// How long the browser waits before reconnecting
+    pub fn retry(&mut self, retry: Duration) -> &mut Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    // every line of the data gets its own `data:` field, line breaks
+    // in the other fields would end them early, so they are removed
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+
+        if let Some(id) = self.id.as_ref() {
+            // ids containing NULL are ignored by browsers
+            put_field(&mut buf, "id", &single_line(id).replace('\0', ""));
+        }
+
+        if let Some(event) = self.event.as_ref() {
+            put_field(&mut buf, "event", &single_line(event));
+        }
+
+        if let Some(retry) = self.retry {
+            put_field(&mut buf, "retry", &retry.as_millis().to_string());
+        }
+
+        if let Some(data) = self.data.as_ref() {
+            for line in data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+                put_field(&mut buf, "data", line);
+            }
+        }
+
+        buf.put_u8(b'\n');
+        buf.freeze()
+    }
+}
+
+fn put_field(buf: &mut BytesMut, name: &str, value: &str) {
+    buf.put_slice(name.as_bytes());
+    buf.put_slice(b": ");
+    buf.put_slice(value.as_bytes());
+    buf.put_u8(b'\n');
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// Sends events to a client which opened an event stream.
+///
+/// Events are written by a separate task, which sends a comment whenever the
+/// stream was idle for the keep-alive interval, so proxies don't close the connection.
+/// It can be cloned to send events from multiple tasks, the stream ends once all clones are dropped.
+#[derive(Clone)]
+pub struct EventStream {
+    queue: mpsc::Sender<Bytes>,
+    last_event_id: Option<String>,
+}
+
+impl EventStream {
+    /// Starts writing to a stream on which the response head was already sent
+    pub fn from_raw<S>(
+        stream: S,
+        last_event_id: Option<String>,
+        keep_alive: Option<Duration>,
+    ) -> Self
+    where
+        S: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (queue, messages) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(EventStream::write_events(stream, messages, keep_alive));
+
+        EventStream {
+            queue,
+            last_event_id,
+        }
+    }
+
+    /// The id of the last event the client received before reconnecting
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Fails once the client disconnected
+    pub async fn send(&self, event: &Event) -> Result<()> {
+        self.send_bytes(event.to_bytes()).await
+    }
+
+    /// Comments are ignored by the client
+    pub async fn comment(&self, comment: &str) -> Result<()> {
+        let mut buf = BytesMut::new();
+        put_field(&mut buf, "", &single_line(comment));
+        buf.put_u8(b'\n');
+        self.send_bytes(buf.freeze()).await
+    }
+
+    /// Resolves once the client disconnected
+    pub async fn closed(&self) {
+        self.queue.closed().await
+    }
+
+    async fn send_bytes(&self, bytes: Bytes) -> Result<()> {
+        self.queue
+            .send(bytes)
+            .await
+            .map_err(|_| anyhow!("event stream: client disconnected"))
+    }
+
+    async fn write_events<S>(
+        mut stream: S,
+        mut messages: mpsc::Receiver<Bytes>,
+        keep_alive: Option<Duration>,
+    ) where
+        S: AsyncWrite + Unpin,
+    {
+        loop {
+            let message = match keep_alive {
+                Some(interval) => match time::timeout(interval, messages.recv()).await {
+                    Ok(message) => message,
+                    Err(_) => Some(Bytes::from_static(b":\n\n")),
+                },
+                None => messages.recv().await,
+            };
+
+            let message = match message {
+                Some(message) => message,
+                None => break,
+            };
+
+            if stream.write_all(&message).await.is_err() || stream.flush().await.is_err() {
+                return;
+            }
+        }
+
+        let _ = stream.shutdown().await;
+    }
+}
+
+impl MiddlewareContext {
+    /// Answers the request with an event stream and takes over the connection,
+    /// no further middlewares are called afterwards
+    pub async fn event_stream(&mut self) -> Result<EventStream> {
+        self.event_stream_with_keep_alive(Some(DEFAULT_KEEP_ALIVE))
+            .await
+    }
+
+    pub async fn event_stream_with_keep_alive(
+        &mut self,
+        keep_alive: Option<Duration>,
+    ) -> Result<EventStream> {
+        self.response.status_code(StatusCode::Ok);
+        self.response.content_type("text/event-stream");
+        self.response.set_header("Cache-Control", "no-cache");
+        // disables response buffering in nginx
+        self.response.set_header("X-Accel-Buffering", "no");
+
+        let last_event_id = self.request.headers.get_str("Last-Event-ID").ok();
+
+        // the stream ends when the connection is closed, so there is no content length
+        let head = self.response.build_head(None);
+        let mut socket = self.take_socket()?;
+        self.end();
+        socket.write_all(&head).await?;
+
+        Ok(EventStream::from_raw(socket, last_event_id, keep_alive))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    #[test]
+    fn serialize_events() {
+        let event = Event::new()
+            .id("1")
+            .event("update")
+            .data("first line\nsecond line\r\nthird")
+            .retry(Duration::from_secs(3))
+            .to_bytes();
+
+        assert_eq!(
+            &event[..],
+            b"id: 1\nevent: update\nretry: 3000\ndata: first line\ndata: second line\ndata: third\n\n"
+        );
+    }
+
+    #[test]
+    fn strip_line_breaks_from_fields() {
+        let event = Event::new().id("a\nb\0").event("x\r\ny").to_bytes();
+        assert_eq!(&event[..], b"id: ab\nevent: xy\n\n");
+    }
+
+    #[tokio::test]
+    async fn send_keep_alive_comments() {
+        let (server, mut client) = duplex(1024);
+        let events = EventStream::from_raw(server, None, Some(Duration::from_millis(10)));
+
+        let mut buf = [0; 3];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b":\n\n");
+
+        events.send(Event::new().data("hi")).await.unwrap();
+        events.comment("note").await.unwrap();
+        drop(events);
+
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        let received = String::from_utf8(buf).unwrap().replace(":\n\n", "");
+        assert_eq!(received, "data: hi\n\n: note\n\n");
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::Result;
+use std::{collections::HashMap, io::BufReader, path::Path, sync::Arc};
+use thiserror::Error;
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::{self, CertifiedKey},
+        Certificate, PrivateKey, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TlsError {
+    #[error("no certificates found in pem file")]
+    NoCertificates,
+    #[error("no private key found in pem file")]
+    NoPrivateKey,
+    #[error("unsupported private key type")]
+    InvalidKey,
+    #[error("no certificates configured")]
+    NotConfigured,
+}
+
+/// Certificates used by a TLS listener.
+///
+/// The certificate for a connection is selected by the server name the client
+/// sent (SNI), the default certificate is used for all other connections.
+#[derive(Clone)]
+pub struct TlsConfig {
+    default: Option<Arc<CertifiedKey>>,
+    server_names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        TlsConfig {
+            default: None,
+            server_names: HashMap::new(),
+        }
+    }
+
+    /// Loads the default certificate chain and private key from pem files
+    pub fn from_pem_files(cert_chain: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let mut config = TlsConfig::new();
+        config.certificate_files(cert_chain, key)?;
+        Ok(config)
+    }
+
+    pub fn certificate(&mut self, cert_chain: &[u8], key: &[u8]) -> Result<&mut Self> {
+        self.default = Some(certified_key(cert_chain, key)?);
+        Ok(self)
+    }
+
+    pub fn certificate_files(
+        &mut self,
+        cert_chain: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<&mut Self> {
+        self.default = Some(certified_key_from_files(cert_chain, key)?);
+        Ok(self)
+    }
+
+    /// Uses a separate certificate for clients connecting to `name`,
+    /// `*.example.com` matches all direct subdomains of `example.com`
+    pub fn server_name(&mut self, name: &str, cert_chain: &[u8], key: &[u8]) -> Result<&mut Self> {
+        self.server_names
+            .insert(name.to_ascii_lowercase(), certified_key(cert_chain, key)?);
+        Ok(self)
+    }
+
+    pub fn server_name_files(
+        &mut self,
+        name: &str,
+        cert_chain: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<&mut Self> {
+        self.server_names.insert(
+            name.to_ascii_lowercase(),
+            certified_key_from_files(cert_chain, key)?,
+        );
+        Ok(self)
+    }
+
+    /// `h2` is only offered using ALPN if the server accepts HTTP/2
+    pub(crate) fn acceptor(&self, http2: bool) -> Result<TlsAcceptor> {
+        if self.default.is_none() && self.server_names.is_empty() {
+            return Err(TlsError::NotConfigured.into());
+        }
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(CertResolver {
+                default: self.default.clone(),
+                server_names: self.server_names.clone(),
+            }));
+        config.alpn_protocols = match http2 {
+            true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            false => vec![b"http/1.1".to_vec()],
+        };
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+struct CertResolver {
+    default: Option<Arc<CertifiedKey>>,
+    server_names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn find(&self, name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let name = match name {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return self.default.clone(),
+        };
+
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+
+        self.server_names
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.server_names.get(&wildcard)))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name())
+    }
+}
+
+fn certified_key_from_files(
+    cert_chain: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+) -> Result<Arc<CertifiedKey>> {
+    let cert_chain = std::fs::read(cert_chain)?;
+    let key = std::fs::read(key)?;
+    certified_key(&cert_chain, &key)
+}
+
+fn certified_key(cert_chain: &[u8], key: &[u8]) -> Result<Arc<CertifiedKey>> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_chain))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates.into());
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(key))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(TlsError::NoPrivateKey)?;
+    let key = sign::any_supported_type(&key).map_err(|_| TlsError::InvalidKey)?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Transport;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        rustls::{self, RootCertStore},
+        TlsConnector,
+    };
+
+    // a self-signed certificate as pem encoded (certificate, key)
+    fn self_signed(name: &str) -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        (
+            cert.serialize_pem().unwrap(),
+            cert.serialize_private_key_pem(),
+        )
+    }
+
+    // connects to `acceptor` and returns the certificate the server presented
+    async fn handshake(acceptor: TlsAcceptor, name: &str, trusted: &str) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(socket).await.unwrap();
+            assert_eq!(stream.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(trusted.as_bytes())).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(name.try_into().unwrap(), socket)
+            .await
+            .unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+
+        let (_, connection) = stream.get_ref();
+        connection.peer_certificates().unwrap()[0].0.clone()
+    }
+
+    #[tokio::test]
+    async fn select_certificate_by_server_name() {
+        let (default_cert, default_key) = self_signed("localhost");
+        let (other_cert, other_key) = self_signed("api.example.com");
+
+        let mut config = TlsConfig::new();
+        config
+            .certificate(default_cert.as_bytes(), default_key.as_bytes())
+            .unwrap()
+            .server_name("*.example.com", other_cert.as_bytes(), other_key.as_bytes())
+            .unwrap();
+        let acceptor = config.acceptor(false).unwrap();
+
+        let presented = handshake(acceptor.clone(), "localhost", &default_cert).await;
+        assert_eq!(pem_der(&default_cert), presented);
+
+        let presented = handshake(acceptor, "api.example.com", &other_cert).await;
+        assert_eq!(pem_der(&other_cert), presented);
+    }
+
+    #[test]
+    fn reject_invalid_pem() {
+        let (cert, key) = self_signed("localhost");
+        let mut config = TlsConfig::new();
+
+        let err = config.certificate(key.as_bytes(), key.as_bytes()).err();
+        assert_eq!(
+            err.unwrap().downcast_ref::<TlsError>(),
+            Some(&TlsError::NoCertificates)
+        );
+
+        let err = config.certificate(cert.as_bytes(), cert.as_bytes()).err();
+        assert_eq!(
+            err.unwrap().downcast_ref::<TlsError>(),
+            Some(&TlsError::NoPrivateKey)
+        );
+
+        assert!(TlsConfig::new().acceptor(false).is_err());
+    }
+
+    fn pem_der(pem: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut BufReader::new(pem.as_bytes())).unwrap()[0].clone()
+    }
+}
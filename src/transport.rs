@@ -0,0 +1,197 @@
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    net::TcpStream,
+};
+use tokio_rustls::server::TlsStream;
+
+/// A connection the server can serve, e.g a tcp socket, a tls session or an in-memory stream
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {
+    /// The address of the client, if the transport has one
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The address the connection was accepted on
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn is_tls(&self) -> bool {
+        false
+    }
+
+    /// The protocol which was negotiated using ALPN, e.g `http/1.1`
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// The server name the client sent (SNI)
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// The user and process on the other end of a unix socket
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        None
+    }
+
+    /// The proxy which sent a PROXY protocol header, `peer_addr` is the original client then
+    fn proxy_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// The transport as it is stored in the `MiddlewareContext`
+pub type BoxedTransport = Box<dyn Transport>;
+
+/// Metadata of a connection, which stays available after the transport was taken over
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionInfo {
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub peer_credentials: Option<PeerCredentials>,
+    pub proxy_addr: Option<SocketAddr>,
+}
+
+impl ConnectionInfo {
+    pub fn from_transport(transport: &dyn Transport) -> Self {
+        ConnectionInfo {
+            peer_addr: transport.peer_addr(),
+            local_addr: transport.local_addr(),
+            peer_credentials: transport.peer_credentials(),
+            proxy_addr: transport.proxy_addr(),
+        }
+    }
+}
+
+/// Credentials of the process which connected to a unix socket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not available on all platforms
+    pub pid: Option<i32>,
+}
+
+impl Transport for BoxedTransport {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn is_tls(&self) -> bool {
+        (**self).is_tls()
+    }
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        (**self).alpn_protocol()
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        (**self).server_name()
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        (**self).peer_credentials()
+    }
+
+    fn proxy_addr(&self) -> Option<SocketAddr> {
+        (**self).proxy_addr()
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+impl<S: Transport> Transport for TlsStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.get_ref().1.alpn_protocol()
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.get_ref().1.sni_hostname()
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.get_ref().0.peer_credentials()
+    }
+
+    fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.proxy_addr()
+    }
+}
+
+// in-memory connections, mostly for tests
+impl Transport for DuplexStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middleware, router::Router, HTTPServer};
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn serve_in_memory_connection() {
+        let mut server = HTTPServer::new();
+        server.get(
+            "/addr",
+            middleware!(|ctx| {
+                let addr = format!("{:?}", ctx.connection.peer_addr);
+                ctx.response.write(addr.as_bytes())
+            }),
+        );
+
+        let (transport, mut client) = duplex(4096);
+        client
+            .write_all(b"GET /addr HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        server.serve_connection(transport).await.unwrap();
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"\r\n\r\nNone"));
+    }
+
+    #[tokio::test]
+    async fn keep_addresses_of_tcp_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let transport: BoxedTransport = Box::new(socket);
+        let info = ConnectionInfo::from_transport(&*transport);
+        assert_eq!(info.peer_addr, Some(client.local_addr().unwrap()));
+        assert_eq!(info.local_addr, Some(listener.local_addr().unwrap()));
+        assert!(!transport.is_tls());
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::Result;
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use thiserror::Error;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::transport::{PeerCredentials, Transport};
+
+#[derive(Error, Debug)]
+pub enum UnixSocketError {
+    #[error("another server is already listening on {0}")]
+    InUse(PathBuf),
+    #[error("{0} exists and is not a socket")]
+    NotASocket(PathBuf),
+}
+
+/// Binds a listener to `path`, removing a socket which was left behind by a previous server.
+///
+/// `mode` sets the permissions of the socket file, e.g `0o660` to only allow
+/// the owner and group to connect.
+pub(crate) fn bind(path: &Path, mode: Option<u32>) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(UnixSocketError::NotASocket(path.to_path_buf()).into())
+        }
+        Ok(_) => {
+            // nobody accepts connections on a stale socket
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => return Err(UnixSocketError::InUse(path.to_path_buf()).into()),
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    match mode {
+        Some(mode) => bind_with_mode(path, mode),
+        None => Ok(UnixListener::bind(path)?),
+    }
+}
+
+// The socket is created in a directory only we can access and moved into place once its
+// permissions are set, so nobody can connect while it still has the default mode.
+fn bind_with_mode(path: &Path, mode: u32) -> Result<UnixListener> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+    })?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}.{}",
+        name.to_string_lossy(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let temp_path = dir.join("socket");
+    let result = UnixListener::bind(&temp_path).and_then(|listener| {
+        fs::set_permissions(&temp_path, Permissions::from_mode(mode))?;
+        fs::rename(&temp_path, path)?;
+        Ok(listener)
+    });
+
+    let _ = fs::remove_file(&temp_path);
+    let removed = fs::remove_dir(&dir);
+    let listener = result?;
+    removed?;
+    Ok(listener)
+}
+
+impl Transport for UnixStream {
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        let cred = self.peer_cred().ok()?;
+        Some(PeerCredentials {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ConnectionInfo;
+    use std::os::unix::fs::MetadataExt;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.sock", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn replace_stale_sockets() {
+        let path = socket_path("stale");
+        drop(bind(&path, None).unwrap());
+        assert!(path.exists());
+
+        let listener = bind(&path, Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // the socket is in use now
+        let err = bind(&path, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<UnixSocketError>(),
+            Some(UnixSocketError::InUse(_))
+        ));
+
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn move_socket_into_place_after_setting_mode() {
+        let path = socket_path("mode");
+        let listener = bind(&path, Some(0o660)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        listener.accept().await.unwrap();
+
+        // the private directory is gone
+        let leftovers = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.starts_with(&format!(".mode-{}.sock.", std::process::id()))
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_other_files() {
+        let path = socket_path("file");
+        fs::write(&path, b"data").unwrap();
+
+        let err = bind(&path, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<UnixSocketError>(),
+            Some(UnixSocketError::NotASocket(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expose_peer_credentials() {
+        let path = socket_path("credentials");
+        let listener = bind(&path, None).unwrap();
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let info = ConnectionInfo::from_transport(&socket);
+        let credentials = info.peer_credentials.unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(credentials.uid, metadata.uid());
+        assert_eq!(credentials.gid, metadata.gid());
+        assert_eq!(credentials.pid, Some(std::process::id() as i32));
+        assert_eq!(info.peer_addr, None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
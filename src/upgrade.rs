@@ -0,0 +1,199 @@
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    http_request::Method, router::MiddlewareContext, transport::BoxedTransport, StatusCode,
+};
+
+/// A connection which was taken over from the http server after a protocol upgrade.
+///
+/// Reading returns the bytes the client sent right after the request first,
+/// e.g the first frames of the new protocol.
+pub struct Upgraded {
+    stream: BoxedTransport,
+    buffered: Bytes,
+}
+
+impl Upgraded {
+    pub fn new(stream: BoxedTransport, buffered: Bytes) -> Self {
+        Upgraded { stream, buffered }
+    }
+
+    /// The underlying stream and the bytes that were already read from it
+    pub fn into_parts(self) -> (BoxedTransport, Bytes) {
+        (self.stream, self.buffered)
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffered.is_empty() {
+            let n = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..n]);
+            self.buffered.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl MiddlewareContext {
+    /// Switches the connection to another protocol and returns it.
+    ///
+    /// Answers with `101 Switching Protocols` (or `200 OK` for `CONNECT` requests) and the
+    /// headers set on `ctx.response`, which must include the `Upgrade` header for the new protocol.
+    /// No further middlewares are called and no other response is written for this request.
+    pub async fn upgrade_connection(&mut self) -> Result<Upgraded> {
+        let status = if self.request.method == Some(Method::CONNECT) {
+            StatusCode::Ok
+        } else {
+            if self.response.header("Upgrade").is_none() {
+                return Err(anyhow!("upgrade: the Upgrade header has to be set"));
+            }
+            if self.response.header("Connection").is_none() {
+                self.response.set_header("Connection", "Upgrade");
+            }
+            StatusCode::SwitchingProtocols
+        };
+
+        let mut head = b"HTTP/1.1 ".to_vec();
+        head.put_slice(status.as_u16().to_string().as_bytes());
+        head.put_slice(b" ");
+        head.put_slice(status.reason_phrase().as_bytes());
+        head.put_slice(b"\r\n");
+        for (key, value) in self.response.headers() {
+            head.put_slice(key.as_bytes());
+            head.put_slice(b": ");
+            head.put_slice(value.as_bytes());
+            head.put_slice(b"\r\n");
+        }
+        head.put_slice(b"\r\n");
+
+        // the socket is marked as raw, so no second response is written
+        let mut socket = self.take_socket()?;
+        self.end();
+        socket.write_all(&head).await?;
+
+        // everything read after the request head belongs to the new protocol
+        let buffered = Bytes::from(std::mem::take(&mut self.request.body));
+        Ok(Upgraded::new(socket, buffered))
+    }
+
+    /// Like [`MiddlewareContext::upgrade_connection`], but runs `handler` with the
+    /// connection in a new task, so the middleware can return right away
+    pub async fn upgrade<F, Fut>(&mut self, handler: F) -> Result<()>
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let upgraded = self.upgrade_connection().await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = handler(upgraded).await {
+                println!("An error occurred on an upgraded connection: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_request::Request, http_response::ResponseBuilder};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    // a context for a request that was received on a real socket, and the client side of it
+    async fn context(request: &[u8]) -> (MiddlewareContext, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let mut parsed = Request::new();
+        parsed.parse(Bytes::copy_from_slice(request)).unwrap();
+        let ctx = MiddlewareContext::new(parsed, ResponseBuilder::new(), Box::new(socket));
+        (ctx, client)
+    }
+
+    #[tokio::test]
+    async fn switch_protocols() {
+        let (mut ctx, mut client) =
+            context(b"GET /chat HTTP/1.1\r\nUpgrade: echo\r\nConnection: upgrade\r\n\r\nhello")
+                .await;
+
+        ctx.response.set_header("Upgrade", "echo");
+        ctx.upgrade(|mut upgraded| async move {
+            let mut buf = [0; 5];
+            upgraded.read_exact(&mut buf).await?;
+            upgraded.write_all(&buf).await?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert!(ctx.has_ended() && ctx.is_raw());
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(
+            &response[..],
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\nhello"
+        );
+    }
+
+    #[tokio::test]
+    async fn answer_connect_with_ok() {
+        let (mut ctx, mut client) =
+            context(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").await;
+
+        let upgraded = ctx.upgrade_connection().await.unwrap();
+        drop(upgraded);
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(&response[..], b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn require_upgrade_header() {
+        let (mut ctx, _client) = context(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(ctx.upgrade_connection().await.is_err());
+        assert!(!ctx.is_raw());
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::Result;
+use thiserror::Error;
+
+use super::{
+    config::WebSocketConfig,
+    deflate::{deflate_offer, parse_deflate_response},
+    handshake::{accept_key, WEBSOCKET_VERSION},
+    stream::{Role, WebSocket},
+};
+use crate::{
+    http_client::{ClientError, Connection, Url},
+    http_request::{Method, Request},
+    transport::BoxedTransport,
+};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConnectError {
+    #[error("expected 101 Switching Protocols, got {0}")]
+    Status(u16),
+    #[error("server didn't upgrade the connection to a websocket")]
+    Upgrade,
+    #[error("invalid Sec-WebSocket-Accept")]
+    Accept,
+    #[error("server selected a protocol we didn't offer")]
+    Protocol,
+    #[error("server selected an extension we can't use")]
+    Extension,
+}
+
+pub async fn connect(url: &str) -> Result<WebSocket> {
+    connect_with_config(url, WebSocketConfig::default()).await
+}
+
+/// Connects to a websocket server at a `ws://` url.
+///
+/// `config.protocols` are offered to the server in order of preference and
+/// `permessage-deflate` is offered if `config.deflate` is set.
+pub async fn connect_with_config(url: &str, config: WebSocketConfig) -> Result<WebSocket> {
+    let url = match url.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("ws") => {
+            Url::parse(&format!("http://{}", rest))?
+        }
+        Some((scheme, _)) => return Err(ClientError::Scheme(scheme.to_string()).into()),
+        None => return Err(ClientError::Url.into()),
+    };
+
+    let key = base64::encode(rand::random::<[u8; 16]>());
+
+    let mut request = Request::new();
+    request.method = Some(Method::GET);
+    request.path = Some(url.path.clone());
+    request.set_header("Host", &url.authority());
+    request.set_header("Connection", "Upgrade");
+    request.set_header("Upgrade", "websocket");
+    request.set_header("Sec-WebSocket-Version", WEBSOCKET_VERSION);
+    request.set_header("Sec-WebSocket-Key", &key);
+    if !config.protocols.is_empty() {
+        request.set_header("Sec-WebSocket-Protocol", &config.protocols.join(", "));
+    }
+    if let Some(deflate) = config.deflate.as_ref() {
+        request.set_header("Sec-WebSocket-Extensions", &deflate_offer(deflate));
+    }
+
+    let mut connection = Connection::connect(&url.host, url.port).await?;
+    connection.send(&request).await?;
+    let response = connection.read_response_head().await?;
+
+    // https://datatracker.ietf.org/doc/html/rfc6455#section-4.2.2
+    let status = response.status_code.as_u16();
+    if status != 101 {
+        return Err(ConnectError::Status(status).into());
+    }
+
+    if !response.headers.contains_token("Connection", "upgrade")
+        || !response.headers.contains_token("Upgrade", "websocket")
+    {
+        return Err(ConnectError::Upgrade.into());
+    }
+
+    match response.headers.get_str("Sec-WebSocket-Accept") {
+        Ok(accept) if accept.trim() == accept_key(&key) => {}
+        _ => return Err(ConnectError::Accept.into()),
+    }
+
+    let protocol = match response.headers.get_str("Sec-WebSocket-Protocol") {
+        Ok(protocol) if config.protocols.iter().any(|p| *p == protocol.trim()) => {
+            Some(protocol.trim().to_string())
+        }
+        Ok(_) => return Err(ConnectError::Protocol.into()),
+        Err(_) => None,
+    };
+
+    let deflate = match response.headers.get_str("Sec-WebSocket-Extensions") {
+        Ok(extensions) => {
+            let config = config.deflate.as_ref().ok_or(ConnectError::Extension)?;
+            let params =
+                parse_deflate_response(extensions.trim(), config).ok_or(ConnectError::Extension)?;
+            Some((params, config.level, config.threshold))
+        }
+        Err(_) => None,
+    };
+
+    // the server might have sent frames right after the handshake
+    let (stream, buffered) = connection.into_inner();
+    let stream: BoxedTransport = Box::new(stream);
+    let mut websocket = WebSocket::from_raw(stream, &buffered, Role::Client, config);
+    websocket.set_protocol(protocol);
+    if let Some((params, level, threshold)) = deflate {
+        websocket.set_deflate(&params, level, threshold);
+    }
+
+    Ok(websocket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::{negotiate_deflate, validate_request, DeflateConfig, Message};
+    use bytes::{Bytes, BytesMut};
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // accepts a single websocket connection and echoes all messages
+    async fn echo_server(extra_headers: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::with_capacity(4096);
+            socket.read_buf(&mut buf).await.unwrap();
+
+            let mut request = Request::new();
+            request.parse(buf.freeze()).unwrap();
+            let accept = validate_request(&request).unwrap();
+
+            let mut response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}",
+                accept, extra_headers
+            );
+            let deflate = request
+                .headers
+                .get_str("Sec-WebSocket-Extensions")
+                .ok()
+                .and_then(|offer| negotiate_deflate(&offer, &DeflateConfig::default()));
+            if let Some(params) = deflate.as_ref() {
+                response.push_str(&format!(
+                    "Sec-WebSocket-Extensions: {}\r\n",
+                    params.to_header()
+                ));
+            }
+            response.push_str("\r\n");
+            socket.write_all(response.as_bytes()).await.unwrap();
+
+            let mut websocket = WebSocket::from_raw(
+                socket,
+                &request.body,
+                Role::Server,
+                WebSocketConfig::default(),
+            );
+            if let Some(params) = deflate {
+                websocket.set_deflate(&params, 6, 0);
+            }
+
+            while let Some(Ok(message)) = websocket.next().await {
+                if let Message::Text(_) | Message::Binary(_) = message {
+                    websocket.send(message).await.unwrap();
+                }
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn connect_to_server() {
+        let port = echo_server("Sec-WebSocket-Protocol: chat\r\n").await;
+        let config = WebSocketConfig {
+            protocols: vec!["superchat".to_string(), "chat".to_string()],
+            deflate: Some(DeflateConfig::default()),
+            ..Default::default()
+        };
+
+        let url = format!("ws://127.0.0.1:{}/chat", port);
+        let mut websocket = connect_with_config(&url, config).await.unwrap();
+        assert_eq!(websocket.protocol(), Some("chat"));
+
+        let text = Message::Text("Hello World ".repeat(100));
+        websocket.send(text.clone()).await.unwrap();
+        assert_eq!(websocket.next().await.unwrap().unwrap(), text);
+
+        let binary = Message::Binary(Bytes::from_static(b"binary"));
+        websocket.send(binary.clone()).await.unwrap();
+        assert_eq!(websocket.next().await.unwrap().unwrap(), binary);
+
+        websocket.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_unexpected_protocol() {
+        let port = echo_server("Sec-WebSocket-Protocol: chat\r\n").await;
+        let url = format!("ws://127.0.0.1:{}/", port);
+
+        let err = connect(&url).await.err().expect("connecting");
+        assert_eq!(
+            err.downcast_ref::<ConnectError>(),
+            Some(&ConnectError::Protocol)
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_other_schemes() {
+        let err = connect("wss://localhost/").await.err().expect("connecting");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::Scheme(_))
+        ));
+    }
+}
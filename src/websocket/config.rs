@@ -0,0 +1,51 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use super::deflate::DeflateConfig;
+
+// the default limit of both servers and clients
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Decides if a connection from the given `Origin` (if the client sent one) is allowed
+pub type OriginPolicy = Arc<dyn Fn(Option<&str>) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct WebSocketConfig {
+    /// Interval in which the server pings the client, `None` disables the heartbeat
+    pub ping_interval: Option<Duration>,
+    /// Time the client has to answer a ping before the connection is closed
+    pub ping_timeout: Duration,
+    /// Supported subprotocols in order of preference, the first one the client also supports is used
+    pub protocols: Vec<String>,
+    /// Connections from origins which aren't allowed are rejected with `403 Forbidden`
+    pub origin: Option<OriginPolicy>,
+    /// Messages larger than this close the connection with status code 1009
+    pub max_message_size: Option<usize>,
+    /// Enables the `permessage-deflate` extension if the client offers it
+    pub deflate: Option<DeflateConfig>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Some(Duration::from_secs(30)),
+            ping_timeout: Duration::from_secs(10),
+            protocols: vec![],
+            origin: None,
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            deflate: None,
+        }
+    }
+}
+
+impl Debug for WebSocketConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketConfig")
+            .field("ping_interval", &self.ping_interval)
+            .field("ping_timeout", &self.ping_timeout)
+            .field("protocols", &self.protocols)
+            .field("origin", &self.origin.as_ref().map(|_| "[originFn]"))
+            .field("max_message_size", &self.max_message_size)
+            .field("deflate", &self.deflate)
+            .finish()
+    }
+}
@@ -0,0 +1,399 @@
+use anyhow::Result;
+use bytes::Bytes;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use super::frame::ProtocolError;
+
+// every message compressed with a sync flush ends with these bytes, which aren't sent
+// https://datatracker.ietf.org/doc/html/rfc7692#section-7.2.1
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+// our compressor always uses the maximum window size
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// Settings for the `permessage-deflate` extension (https://datatracker.ietf.org/doc/html/rfc7692)
+#[derive(Debug, Clone)]
+pub struct DeflateConfig {
+    /// Reset our compression context after every message, using less memory but compressing worse
+    pub server_no_context_takeover: bool,
+    /// Ask the client to reset its compression context after every message
+    pub client_no_context_takeover: bool,
+    /// Limit the window size used by the client (between 8 and 15), if it supports that
+    pub client_max_window_bits: Option<u8>,
+    /// Compression level between 0 and 9
+    pub level: u32,
+    /// Messages smaller than this are sent uncompressed
+    pub threshold: usize,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        Self {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            client_max_window_bits: None,
+            level: 6,
+            threshold: 64,
+        }
+    }
+}
+
+/// The parameters both sides agreed on during the handshake
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub client_max_window_bits: Option<u8>,
+}
+
+impl DeflateParams {
+    /// The value of the `Sec-WebSocket-Extensions` response header
+    pub fn to_header(&self) -> String {
+        let mut header = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if let Some(bits) = self.client_max_window_bits {
+            header.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+        header
+    }
+}
+
+/// Picks the first `permessage-deflate` offer from a `Sec-WebSocket-Extensions` header we can accept
+pub fn negotiate_deflate(offers: &str, config: &DeflateConfig) -> Option<DeflateParams> {
+    offers
+        .split(',')
+        .find_map(|offer| negotiate_offer(offer, config))
+}
+
+fn negotiate_offer(offer: &str, config: &DeflateConfig) -> Option<DeflateParams> {
+    let mut server_no_context_takeover = config.server_no_context_takeover;
+    let mut client_no_context_takeover = config.client_no_context_takeover;
+    let mut client_max_window_bits = None;
+
+    for (name, value) in parse_params(offer)? {
+        match (name, value) {
+            ("server_no_context_takeover", None) => server_no_context_takeover = true,
+            ("client_no_context_takeover", None) => client_no_context_takeover = true,
+            // we can only compress with the maximum window size
+            ("server_max_window_bits", Some(bits)) => {
+                if parse_window_bits(bits)? != MAX_WINDOW_BITS {
+                    return None;
+                }
+            }
+            // the client supports a smaller window, which we only ask for if configured
+            ("client_max_window_bits", bits) => {
+                let offered = match bits {
+                    Some(bits) => parse_window_bits(bits)?,
+                    None => MAX_WINDOW_BITS,
+                };
+                client_max_window_bits = config
+                    .client_max_window_bits
+                    .map(|bits| bits.clamp(8, MAX_WINDOW_BITS).min(offered))
+                    .or_else(|| bits.map(|_| offered));
+            }
+            _ => return None,
+        }
+    }
+
+    Some(DeflateParams {
+        server_no_context_takeover,
+        client_no_context_takeover,
+        client_max_window_bits,
+    })
+}
+
+/// The `Sec-WebSocket-Extensions` header a client sends to offer `permessage-deflate`
+pub fn deflate_offer(config: &DeflateConfig) -> String {
+    let mut offer = "permessage-deflate".to_string();
+    if config.server_no_context_takeover {
+        offer.push_str("; server_no_context_takeover");
+    }
+    if config.client_no_context_takeover {
+        offer.push_str("; client_no_context_takeover");
+    }
+    offer
+}
+
+/// Parses the server's answer to our offer, fails on parameters we can't handle
+pub fn parse_deflate_response(response: &str, config: &DeflateConfig) -> Option<DeflateParams> {
+    let mut params = DeflateParams {
+        server_no_context_takeover: false,
+        client_no_context_takeover: config.client_no_context_takeover,
+        client_max_window_bits: None,
+    };
+
+    for (name, value) in parse_params(response)? {
+        match (name, value) {
+            ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+            ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+            // the server's window size doesn't matter for decompressing
+            ("server_max_window_bits", Some(bits)) => {
+                parse_window_bits(bits)?;
+            }
+            // we didn't offer to use a smaller window
+            ("client_max_window_bits", Some(bits)) => {
+                if parse_window_bits(bits)? != MAX_WINDOW_BITS {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    Some(params)
+}
+
+// splits a single `permessage-deflate` extension into its parameters,
+// which must not be repeated
+fn parse_params(extension: &str) -> Option<Vec<(&str, Option<&str>)>> {
+    let mut params = extension.split(';').map(str::trim);
+    if params.next()? != "permessage-deflate" {
+        return None;
+    }
+
+    let mut parsed: Vec<(&str, Option<&str>)> = vec![];
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+
+        if parsed.iter().any(|(seen, _)| *seen == name) {
+            return None;
+        }
+        parsed.push((name, value));
+    }
+
+    Some(parsed)
+}
+
+fn parse_window_bits(bits: &str) -> Option<u8> {
+    match bits.parse() {
+        Ok(bits) if (8..=15).contains(&bits) => Some(bits),
+        _ => None,
+    }
+}
+
+/// Compresses outgoing messages
+pub struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+    threshold: usize,
+}
+
+impl Deflater {
+    pub fn new(level: u32, threshold: usize, no_context_takeover: bool) -> Self {
+        Self {
+            compress: Compress::new(Compression::new(level.min(9)), false),
+            no_context_takeover,
+            threshold,
+        }
+    }
+
+    // small messages usually get bigger when compressed
+    pub fn should_compress(&self, payload: &[u8]) -> bool {
+        payload.len() >= self.threshold
+    }
+
+    pub fn compress(&mut self, payload: &[u8]) -> Result<Bytes> {
+        let mut output = Vec::with_capacity(payload.len() / 2 + 64);
+        let mut consumed = 0;
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity().max(64));
+            }
+
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(&payload[consumed..], &mut output, FlushCompress::Sync)?;
+            consumed += (self.compress.total_in() - before) as usize;
+
+            // the flush is complete once there is output space left
+            if consumed == payload.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&DEFLATE_TRAILER) {
+            output.truncate(output.len() - DEFLATE_TRAILER.len());
+        }
+
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+
+        Ok(Bytes::from(output))
+    }
+}
+
+/// Decompresses incoming messages which have the `rsv1` bit set
+pub struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Inflater {
+    pub fn new(no_context_takeover: bool) -> Self {
+        Self {
+            decompress: Decompress::new(false),
+            no_context_takeover,
+        }
+    }
+
+    /// Fails once the decompressed message grows beyond `max_size`
+    pub fn decompress(&mut self, payload: &[u8], max_size: usize) -> Result<Bytes> {
+        let mut input = Vec::with_capacity(payload.len() + DEFLATE_TRAILER.len());
+        input.extend_from_slice(payload);
+        input.extend_from_slice(&DEFLATE_TRAILER);
+
+        let mut output = Vec::with_capacity(payload.len() * 2 + 64);
+        let mut consumed = 0;
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            let (before_in, before_out) = (self.decompress.total_in(), output.len());
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|_| ProtocolError::InvalidCompressedData)?;
+            consumed += (self.decompress.total_in() - before_in) as usize;
+
+            // the peer ended the deflate stream, a new one starts with the next message
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                return Ok(Bytes::from(output));
+            }
+
+            if output.len() > max_size {
+                return Err(ProtocolError::MessageTooBig(max_size).into());
+            }
+
+            if consumed == input.len() && output.len() < output.capacity() {
+                break;
+            }
+
+            let progress = self.decompress.total_in() != before_in || output.len() != before_out;
+            if !progress && output.len() < output.capacity() {
+                return Err(ProtocolError::InvalidCompressedData.into());
+            }
+        }
+
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(Bytes::from(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_offers() {
+        let config = DeflateConfig::default();
+
+        let params =
+            negotiate_deflate("permessage-deflate; client_max_window_bits", &config).unwrap();
+        assert_eq!(params.to_header(), "permessage-deflate");
+
+        // offers we can't accept are skipped
+        let params = negotiate_deflate(
+            "permessage-deflate; server_max_window_bits=10, permessage-deflate; server_no_context_takeover",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(
+            params.to_header(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+
+        assert_eq!(negotiate_deflate("x-webkit-deflate-frame", &config), None);
+        assert_eq!(negotiate_deflate("permessage-deflate; foo", &config), None);
+    }
+
+    #[test]
+    fn limit_client_window_bits() {
+        let config = DeflateConfig {
+            client_max_window_bits: Some(10),
+            ..Default::default()
+        };
+
+        let params =
+            negotiate_deflate("permessage-deflate; client_max_window_bits", &config).unwrap();
+        assert_eq!(
+            params.to_header(),
+            "permessage-deflate; client_max_window_bits=10"
+        );
+
+        // we can't ask for a limit if the client doesn't support it
+        let params = negotiate_deflate("permessage-deflate", &config).unwrap();
+        assert_eq!(params.client_max_window_bits, None);
+    }
+
+    #[test]
+    fn parse_server_response() {
+        let config = DeflateConfig::default();
+        assert_eq!(deflate_offer(&config), "permessage-deflate");
+
+        let params =
+            parse_deflate_response("permessage-deflate; server_no_context_takeover", &config)
+                .unwrap();
+        assert!(params.server_no_context_takeover);
+        assert!(!params.client_no_context_takeover);
+
+        // we can't compress with a smaller window
+        assert_eq!(
+            parse_deflate_response("permessage-deflate; client_max_window_bits=9", &config),
+            None
+        );
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let mut deflater = Deflater::new(6, 0, false);
+        let mut inflater = Inflater::new(false);
+
+        let message = "Hello Hello Hello Hello Hello Hello".repeat(100);
+        for _ in 0..3 {
+            let compressed = deflater.compress(message.as_bytes()).unwrap();
+            assert!(compressed.len() < message.len());
+            assert!(!compressed.ends_with(&DEFLATE_TRAILER));
+
+            let decompressed = inflater.decompress(&compressed, usize::MAX).unwrap();
+            assert_eq!(&decompressed[..], message.as_bytes());
+        }
+    }
+
+    #[test]
+    fn decompress_rfc_example() {
+        // "Hello" from https://datatracker.ietf.org/doc/html/rfc7692#section-7.2.3.1
+        let mut inflater = Inflater::new(false);
+        let decompressed = inflater
+            .decompress(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], usize::MAX)
+            .unwrap();
+        assert_eq!(&decompressed[..], b"Hello");
+    }
+
+    #[test]
+    fn limit_decompressed_size() {
+        let mut deflater = Deflater::new(6, 0, false);
+        let compressed = deflater.compress(&[0; 100_000]).unwrap();
+
+        let mut inflater = Inflater::new(false);
+        let err = inflater.decompress(&compressed, 1000).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::MessageTooBig(1000))
+        );
+    }
+}
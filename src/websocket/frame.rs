@@ -1,7 +1,54 @@
 use anyhow::{anyhow, Result};
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
 
-// use super::mask::apply_mask;
+use super::{mask::apply_mask, message::close_code};
+
+/// Violations of https://datatracker.ietf.org/doc/html/rfc6455 by the peer,
+/// the connection is closed with the matching close code
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    #[error("reserved bits set without a negotiated extension")]
+    ReservedBits,
+    #[error("unknown opcode: {0}")]
+    UnknownOpCode(u8),
+    #[error("control frames must not be fragmented")]
+    FragmentedControlFrame,
+    #[error("control frame payload larger than 125 bytes")]
+    ControlFrameTooLarge,
+    #[error("frames from clients must be masked")]
+    UnmaskedFrame,
+    #[error("frames from servers must not be masked")]
+    MaskedFrame,
+    #[error("payload length uses the most significant bit")]
+    InvalidLength,
+    #[error("unexpected continuation frame")]
+    UnexpectedContinuation,
+    #[error("expected continuation frame")]
+    ExpectedContinuation,
+    #[error("invalid close frame")]
+    InvalidCloseFrame,
+    #[error("invalid close code: {0}")]
+    InvalidCloseCode(u16),
+    #[error("text is not valid utf8")]
+    InvalidUtf8,
+    #[error("invalid compressed data")]
+    InvalidCompressedData,
+    #[error("message larger than {0} bytes")]
+    MessageTooBig(usize),
+}
+
+impl ProtocolError {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            ProtocolError::InvalidUtf8 | ProtocolError::InvalidCompressedData => {
+                close_code::INVALID_PAYLOAD
+            }
+            ProtocolError::MessageTooBig(_) => close_code::MESSAGE_TOO_BIG,
+            _ => close_code::PROTOCOL_ERROR,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct FrameHeader {
@@ -9,25 +56,134 @@ pub struct FrameHeader {
     pub rsv1: bool,
     pub rsv2: bool,
     pub rsv3: bool,
-    pub opcode: u8,
+    pub opcode: OpCode,
     pub mask: Option<[u8; 4]>,
     pub data_length: DataLength,
     pub header_length: u64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DataLength {
     Small(u8),
     Medium(u16),
     Large(u64),
 }
 
+impl DataLength {
+    // Uses the shortest encoding for the payload length
+    pub fn new(length: u64) -> Self {
+        match length {
+            0..=125 => DataLength::Small(length as u8),
+            126..=0xffff => DataLength::Medium(length as u16),
+            _ => DataLength::Large(length),
+        }
+    }
+
+    pub fn length(&self) -> u64 {
+        match *self {
+            DataLength::Small(len) => len as u64,
+            DataLength::Medium(len) => len as u64,
+            DataLength::Large(len) => len,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    Reserved(u8),
+}
+
+impl OpCode {
+    // Control frames can be sent in the middle of fragmented messages
+    pub fn is_control(&self) -> bool {
+        u8::from(*self) & 0x08 != 0
+    }
+}
+
+impl From<u8> for OpCode {
+    fn from(opcode: u8) -> Self {
+        match opcode {
+            0x0 => OpCode::Continuation,
+            0x1 => OpCode::Text,
+            0x2 => OpCode::Binary,
+            0x8 => OpCode::Close,
+            0x9 => OpCode::Ping,
+            0xa => OpCode::Pong,
+            opcode => OpCode::Reserved(opcode & 0x0f),
+        }
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(opcode: OpCode) -> Self {
+        match opcode {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xa,
+            OpCode::Reserved(opcode) => opcode & 0x0f,
+        }
+    }
+}
+
 impl FrameHeader {
-    // pub fn to_bytes(&self) -> BytesMut {
-    //     unimplemented!()
+    pub fn new(opcode: OpCode, length: u64) -> Self {
+        let data_length = DataLength::new(length);
+        let header_length = match data_length {
+            DataLength::Small(_) => 2,
+            DataLength::Medium(_) => 4,
+            DataLength::Large(_) => 10,
+        };
+
+        Self {
+            fin: true,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode,
+            mask: None,
+            data_length,
+            header_length,
+        }
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(self.header_length as usize);
+
+        let first = (self.fin as u8) << 7
+            | (self.rsv1 as u8) << 6
+            | (self.rsv2 as u8) << 5
+            | (self.rsv3 as u8) << 4
+            | u8::from(self.opcode);
+        buf.put_u8(first);
 
-    //     // let mut buf = BytesMut::new();
-    // }
+        let masked = (self.mask.is_some() as u8) << 7;
+        match self.data_length {
+            DataLength::Small(len) => buf.put_u8(masked | len),
+            DataLength::Medium(len) => {
+                buf.put_u8(masked | 126);
+                buf.put_u16(len);
+            }
+            DataLength::Large(len) => {
+                buf.put_u8(masked | 127);
+                buf.put_u64(len);
+            }
+        }
+
+        if let Some(mask) = self.mask {
+            buf.put_slice(&mask);
+        }
+
+        buf
+    }
 
     pub fn from_bytes(buf: &mut Bytes) -> Result<Self> {
         if buf.len() < 2 {
@@ -42,7 +198,7 @@ impl FrameHeader {
         let rsv2 = (first & 0x20) != 0;
         let rsv3 = (first & 0x10) != 0;
         let masked = (second & 0x80) != 0;
-        let opcode = first & 0x0f;
+        let opcode = OpCode::from(first & 0x0f);
 
         let mut header_length = 2;
         let length_byte = second & 0x7F;
@@ -50,30 +206,30 @@ impl FrameHeader {
         let data_length: DataLength = match length_byte {
             // Extended payload length continued, if payload len == 127
             127 => {
-                if buf.len() < 10 {
-                    return Err(anyhow!("payload: length too large: {}", buf.len()));
+                if buf.len() < 8 {
+                    return Err(anyhow!("payload: length too short: {}", buf.len()));
+                }
+
+                // the most significant bit must be 0
+                let len = buf.get_u64();
+                if len >> 63 != 0 {
+                    return Err(ProtocolError::InvalidLength.into());
                 }
 
                 header_length += 8;
-                DataLength::Large(buf.get_u64())
+                DataLength::Large(len)
             }
             // Extended payload length, (if payload len==126/127)
             126 => {
-                if buf.len() < 4 {
-                    return Err(anyhow!("payload: length too large: {}", buf.len()));
+                if buf.len() < 2 {
+                    return Err(anyhow!("payload: length too short: {}", buf.len()));
                 }
 
                 header_length += 2;
                 DataLength::Medium(buf.get_u16())
             }
             // Payload len (7)
-            len => {
-                if len > 126 {
-                    return Err(anyhow!("payload: length too large: {}", len));
-                }
-
-                DataLength::Small(len)
-            }
+            len => DataLength::Small(len),
         };
 
         let mask = if masked {
@@ -99,3 +255,150 @@ impl FrameHeader {
         })
     }
 }
+
+/// A single websocket frame, consisting of a header and its (unmasked) payload
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub payload: Bytes,
+}
+
+impl Frame {
+    pub fn new(opcode: OpCode, payload: Bytes) -> Self {
+        Self {
+            header: FrameHeader::new(opcode, payload.len() as u64),
+            payload,
+        }
+    }
+
+    pub fn text(text: &str) -> Self {
+        Frame::new(OpCode::Text, Bytes::copy_from_slice(text.as_bytes()))
+    }
+
+    pub fn binary(data: Bytes) -> Self {
+        Frame::new(OpCode::Binary, data)
+    }
+
+    pub fn ping(data: Bytes) -> Self {
+        Frame::new(OpCode::Ping, data)
+    }
+
+    pub fn pong(data: Bytes) -> Self {
+        Frame::new(OpCode::Pong, data)
+    }
+
+    // The payload of a close frame is an optional status code followed by a reason
+    pub fn close(code: Option<u16>, reason: &str) -> Self {
+        let mut payload = BytesMut::new();
+        if let Some(code) = code {
+            payload.put_u16(code);
+            payload.put_slice(reason.as_bytes());
+        }
+        Frame::new(OpCode::Close, payload.freeze())
+    }
+
+    /// Marks the frame as the last (or not last) frame of a message
+    pub fn fin(&mut self, fin: bool) -> &mut Self {
+        self.header.fin = fin;
+        self
+    }
+
+    /// Masks the payload with the given key once serialized (required for client frames)
+    pub fn mask(&mut self, mask: [u8; 4]) -> &mut Self {
+        if self.header.mask.is_none() {
+            self.header.header_length += 4;
+        }
+        self.header.mask = Some(mask);
+        self
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = self.header.to_bytes();
+        buf.reserve(self.payload.len());
+
+        let start = buf.len();
+        buf.put_slice(&self.payload);
+        if let Some(mask) = self.header.mask {
+            apply_mask(&mut buf[start..], mask);
+        }
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: &Frame) -> (FrameHeader, Bytes) {
+        let mut buf = frame.to_bytes().freeze();
+        let header = FrameHeader::from_bytes(&mut buf).expect("parsing header");
+
+        let mut payload = buf.to_vec();
+        if let Some(mask) = header.mask {
+            apply_mask(&mut payload, mask);
+        }
+        (header, Bytes::from(payload))
+    }
+
+    #[test]
+    fn encode_payload_lengths() {
+        for (length, header_length) in [(0, 2), (125, 2), (126, 4), (65535, 4), (65536, 10)] {
+            let frame = Frame::binary(Bytes::from(vec![7; length]));
+            assert_eq!(frame.to_bytes().len(), length + header_length);
+
+            let (header, payload) = round_trip(&frame);
+            assert_eq!(header.data_length.length(), length as u64);
+            assert_eq!(header.header_length, header_length as u64);
+            assert_eq!(payload, frame.payload);
+        }
+    }
+
+    #[test]
+    fn encode_masked_frames() {
+        let mut frame = Frame::text("Hello");
+        frame.mask([0x37, 0xfa, 0x21, 0x3d]);
+
+        // example from https://datatracker.ietf.org/doc/html/rfc6455#section-5.7
+        assert_eq!(
+            &frame.to_bytes()[..],
+            &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]
+        );
+
+        let (header, payload) = round_trip(&frame);
+        assert_eq!(header.mask, Some([0x37, 0xfa, 0x21, 0x3d]));
+        assert_eq!(header.header_length, 6);
+        assert_eq!(&payload[..], b"Hello");
+    }
+
+    #[test]
+    fn encode_all_opcodes() {
+        let frames = [
+            (Frame::new(OpCode::Continuation, Bytes::new()), 0x0),
+            (Frame::text("hi"), 0x1),
+            (Frame::binary(Bytes::from_static(b"hi")), 0x2),
+            (Frame::close(Some(1000), "bye"), 0x8),
+            (Frame::ping(Bytes::new()), 0x9),
+            (Frame::pong(Bytes::new()), 0xa),
+        ];
+
+        for (mut frame, opcode) in frames {
+            frame.fin(false);
+            let bytes = frame.to_bytes();
+            assert_eq!(bytes[0], opcode);
+
+            let (header, payload) = round_trip(&frame);
+            assert_eq!(header.opcode, frame.header.opcode);
+            assert!(!header.fin);
+            assert_eq!(payload, frame.payload);
+        }
+    }
+
+    #[test]
+    fn encode_close_payload() {
+        let frame = Frame::close(Some(1001), "going away");
+        assert_eq!(&frame.payload[..2], &[0x03, 0xe9]);
+        assert_eq!(&frame.payload[2..], b"going away");
+        assert!(Frame::close(None, "").payload.is_empty());
+    }
+}
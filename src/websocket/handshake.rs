@@ -0,0 +1,188 @@
+use httpstatus::StatusCode;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use super::config::WebSocketConfig;
+use crate::http_request::{Method, Request};
+
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub const WEBSOCKET_VERSION: &str = "13";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum HandshakeError {
+    #[error("not a websocket upgrade request")]
+    NotUpgrade,
+    #[error("websocket upgrades require a GET request")]
+    Method,
+    #[error("websocket upgrades require HTTP/1.1")]
+    HttpVersion,
+    #[error("missing host header")]
+    Host,
+    #[error("invalid Sec-WebSocket-Key")]
+    Key,
+    #[error("unsupported websocket version")]
+    Version,
+    #[error("origin not allowed")]
+    Origin,
+}
+
+impl HandshakeError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            HandshakeError::Version => StatusCode::UpgradeRequired,
+            HandshakeError::Origin => StatusCode::Forbidden,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+// Checks if the client asked to upgrade the connection to a websocket
+pub fn is_upgrade_request(request: &Request) -> bool {
+    request.headers.contains_token("Connection", "upgrade")
+        && request.headers.contains_token("Upgrade", "websocket")
+}
+
+/// Validates an opening handshake (https://datatracker.ietf.org/doc/html/rfc6455#section-4.2.1)
+/// and returns the value of the `Sec-WebSocket-Accept` header
+pub fn validate_request(request: &Request) -> Result<String, HandshakeError> {
+    if !is_upgrade_request(request) {
+        return Err(HandshakeError::NotUpgrade);
+    }
+
+    if request.method != Some(Method::GET) {
+        return Err(HandshakeError::Method);
+    }
+
+    if request.version != Some(1) {
+        return Err(HandshakeError::HttpVersion);
+    }
+
+    if !request.headers.contains("Host") {
+        return Err(HandshakeError::Host);
+    }
+
+    match request.headers.get_str("Sec-WebSocket-Version") {
+        Ok(version) if version.trim() == WEBSOCKET_VERSION => {}
+        _ => return Err(HandshakeError::Version),
+    }
+
+    // the key has to be a base64 encoded 16 byte nonce
+    let key = request
+        .headers
+        .get_str("Sec-WebSocket-Key")
+        .map_err(|_| HandshakeError::Key)?;
+    let key = key.trim();
+    match base64::decode(key) {
+        Ok(nonce) if nonce.len() == 16 => Ok(accept_key(key)),
+        _ => Err(HandshakeError::Key),
+    }
+}
+
+// Checks the `Origin` of the request against the configured policy
+pub fn validate_origin(request: &Request, config: &WebSocketConfig) -> Result<(), HandshakeError> {
+    let policy = match config.origin.as_ref() {
+        Some(policy) => policy,
+        None => return Ok(()),
+    };
+
+    let origin = request.headers.get_str("Origin").ok();
+    if policy(origin.as_deref().map(str::trim)) {
+        Ok(())
+    } else {
+        Err(HandshakeError::Origin)
+    }
+}
+
+/// Picks the first of our supported subprotocols which the client requested
+pub fn negotiate_protocol(request: &Request, config: &WebSocketConfig) -> Option<String> {
+    let requested = request.headers.get_str("Sec-WebSocket-Protocol").ok()?;
+    let requested: Vec<&str> = requested.split(',').map(str::trim).collect();
+
+    config
+        .protocols
+        .iter()
+        .find(|protocol| requested.contains(&protocol.as_str()))
+        .cloned()
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID);
+    base64::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn request(raw: &'static [u8]) -> Request {
+        let mut request = Request::new();
+        request
+            .parse(Bytes::from_static(raw))
+            .expect("parsing request");
+        request
+    }
+
+    #[test]
+    fn accept_valid_handshakes() {
+        let request = request(b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n");
+
+        // example from https://datatracker.ietf.org/doc/html/rfc6455#section-1.3
+        assert_eq!(
+            validate_request(&request),
+            Ok("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string())
+        );
+    }
+
+    #[test]
+    fn do_not_accept_invalid_handshakes() {
+        let cases: [(&'static [u8], HandshakeError); 5] = [
+            (b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\n", HandshakeError::NotUpgrade),
+            (b"POST / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", HandshakeError::Method),
+            (b"GET / HTTP/1.0\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n", HandshakeError::HttpVersion),
+            (b"GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n", HandshakeError::Version),
+            (b"GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: abc\r\nSec-WebSocket-Version: 13\r\n\r\n", HandshakeError::Key),
+        ];
+
+        for (raw, error) in cases {
+            assert_eq!(validate_request(&request(raw)), Err(error));
+        }
+    }
+
+    #[test]
+    fn negotiate_protocols() {
+        let request =
+            request(b"GET / HTTP/1.1\r\nSec-WebSocket-Protocol: chat, graphql-ws\r\n\r\n");
+        let mut config = WebSocketConfig::default();
+        assert_eq!(negotiate_protocol(&request, &config), None);
+
+        config.protocols = vec!["graphql-ws".to_string(), "chat".to_string()];
+        assert_eq!(
+            negotiate_protocol(&request, &config),
+            Some("graphql-ws".to_string())
+        );
+
+        config.protocols = vec!["mqtt".to_string()];
+        assert_eq!(negotiate_protocol(&request, &config), None);
+    }
+
+    #[test]
+    fn check_origins() {
+        let request = request(b"GET / HTTP/1.1\r\nOrigin: https://example.com\r\n\r\n");
+        let mut config = WebSocketConfig::default();
+        assert_eq!(validate_origin(&request, &config), Ok(()));
+
+        config.origin = Some(std::sync::Arc::new(|origin| {
+            origin == Some("https://example.com")
+        }));
+        assert_eq!(validate_origin(&request, &config), Ok(()));
+
+        let request = Request::new();
+        assert_eq!(
+            validate_origin(&request, &config),
+            Err(HandshakeError::Origin)
+        );
+    }
+}
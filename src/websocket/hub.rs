@@ -0,0 +1,349 @@
+use futures::SinkExt;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc::{self, error::TrySendError},
+};
+
+use super::{
+    message::{close_code, CloseFrame, Message, PreparedMessage},
+    stream::WebSocketWriter,
+};
+
+const DEFAULT_QUEUE_SIZE: usize = 256;
+
+/// What happens to connections whose queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowConsumer {
+    /// The message is dropped for this connection only
+    Drop,
+    /// The connection is removed from the hub and closed with status code 1008
+    Disconnect,
+}
+
+/// Fans out messages to many websocket connections, grouped into named rooms.
+///
+/// Broadcasts are serialized once and every connection has its own bounded queue,
+/// which is written to the socket by a separate task, so slow clients never block the sender.
+#[derive(Clone)]
+pub struct Hub {
+    state: Arc<Mutex<HubState>>,
+    queue_size: usize,
+    slow_consumer: SlowConsumer,
+}
+
+#[derive(Default)]
+struct HubState {
+    next_id: u64,
+    members: HashMap<u64, Subscriber>,
+    rooms: HashMap<String, HashSet<u64>>,
+}
+
+struct Subscriber {
+    queue: mpsc::Sender<PreparedMessage>,
+    rooms: HashSet<String>,
+    // set once the connection was removed because it couldn't keep up
+    kicked: Arc<AtomicBool>,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Hub {
+            state: Arc::new(Mutex::new(HubState::default())),
+            queue_size: DEFAULT_QUEUE_SIZE,
+            slow_consumer: SlowConsumer::Drop,
+        }
+    }
+
+    /// Number of messages which can be queued for a single connection
+    pub fn queue_size(&mut self, queue_size: usize) -> &mut Self {
+        self.queue_size = queue_size.max(1);
+        self
+    }
+
+    pub fn slow_consumer(&mut self, slow_consumer: SlowConsumer) -> &mut Self {
+        self.slow_consumer = slow_consumer;
+        self
+    }
+
+    /// Adds a connection to the hub, it isn't part of any room yet.
+    ///
+    /// Queued messages are written by a new task until the returned [`Member`] is dropped
+    /// or the connection fails, then the connection is closed.
+    pub fn connect<S>(&self, mut writer: WebSocketWriter<S>) -> Member
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (queue, mut messages) = mpsc::channel::<PreparedMessage>(self.queue_size);
+        let kicked = Arc::new(AtomicBool::new(false));
+
+        let id = {
+            let mut state = self.state.lock();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.members.insert(
+                id,
+                Subscriber {
+                    queue,
+                    rooms: HashSet::new(),
+                    kicked: kicked.clone(),
+                },
+            );
+            id
+        };
+
+        let hub = self.clone();
+        tokio::spawn(async move {
+            while let Some(message) = messages.recv().await {
+                // write everything that is queued at once
+                let mut batch = vec![message];
+                while let Ok(message) = messages.try_recv() {
+                    batch.push(message);
+                }
+
+                if writer.send_prepared(batch).await.is_err() {
+                    hub.remove(id);
+                    return;
+                }
+            }
+
+            let close = if kicked.load(Ordering::Relaxed) {
+                CloseFrame::new(close_code::POLICY_VIOLATION, "too slow")
+            } else {
+                CloseFrame::new(close_code::GOING_AWAY, "")
+            };
+            let _ = writer.send(Message::Close(Some(close))).await;
+        });
+
+        Member {
+            id,
+            hub: self.clone(),
+        }
+    }
+
+    /// Sends a message to all connections in a room and returns how many it was queued for
+    pub fn broadcast(&self, room: &str, message: &Message) -> usize {
+        self.broadcast_prepared(room, &PreparedMessage::new(message))
+    }
+
+    pub fn broadcast_prepared(&self, room: &str, message: &PreparedMessage) -> usize {
+        let mut state = self.state.lock();
+        let ids: Vec<u64> = match state.rooms.get(room) {
+            Some(ids) => ids.iter().copied().collect(),
+            None => return 0,
+        };
+
+        ids.into_iter()
+            .filter(|id| self.deliver(&mut state, *id, message))
+            .count()
+    }
+
+    /// Names of all rooms with at least one connection
+    pub fn rooms(&self) -> Vec<String> {
+        self.state.lock().rooms.keys().cloned().collect()
+    }
+
+    pub fn room_size(&self, room: &str) -> usize {
+        self.state.lock().rooms.get(room).map_or(0, |ids| ids.len())
+    }
+
+    /// Number of connections in the hub
+    pub fn len(&self) -> usize {
+        self.state.lock().members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // queues a message for a single connection, applying the slow consumer policy
+    fn deliver(&self, state: &mut HubState, id: u64, message: &PreparedMessage) -> bool {
+        let subscriber = match state.members.get(&id) {
+            Some(subscriber) => subscriber,
+            None => return false,
+        };
+
+        match subscriber.queue.try_send(message.clone()) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) if self.slow_consumer == SlowConsumer::Drop => false,
+            Err(TrySendError::Full(_)) => {
+                subscriber.kicked.store(true, Ordering::Relaxed);
+                HubState::remove(state, id);
+                false
+            }
+            Err(TrySendError::Closed(_)) => {
+                HubState::remove(state, id);
+                false
+            }
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        HubState::remove(&mut self.state.lock(), id);
+    }
+}
+
+impl HubState {
+    fn remove(state: &mut HubState, id: u64) {
+        let subscriber = match state.members.remove(&id) {
+            Some(subscriber) => subscriber,
+            None => return,
+        };
+
+        for room in subscriber.rooms {
+            HubState::leave_room(state, &room, id);
+        }
+    }
+
+    fn leave_room(state: &mut HubState, room: &str, id: u64) {
+        if let Some(ids) = state.rooms.get_mut(room) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                state.rooms.remove(room);
+            }
+        }
+    }
+}
+
+/// A connection which was added to a [`Hub`], it is removed from the hub once dropped
+pub struct Member {
+    id: u64,
+    hub: Hub,
+}
+
+impl Member {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn join(&self, room: &str) {
+        let mut state = self.hub.state.lock();
+        if let Some(subscriber) = state.members.get_mut(&self.id) {
+            subscriber.rooms.insert(room.to_string());
+            state
+                .rooms
+                .entry(room.to_string())
+                .or_default()
+                .insert(self.id);
+        }
+    }
+
+    pub fn leave(&self, room: &str) {
+        let mut state = self.hub.state.lock();
+        if let Some(subscriber) = state.members.get_mut(&self.id) {
+            subscriber.rooms.remove(room);
+            HubState::leave_room(&mut state, room, self.id);
+        }
+    }
+
+    /// Queues a message for this connection only
+    pub fn send(&self, message: &Message) -> bool {
+        let mut state = self.hub.state.lock();
+        self.hub
+            .deliver(&mut state, self.id, &PreparedMessage::new(message))
+    }
+
+    /// False once the connection was removed from the hub, e.g because it was too slow
+    pub fn is_connected(&self) -> bool {
+        self.hub.state.lock().members.contains_key(&self.id)
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        self.hub.remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::{Role, WebSocket, WebSocketConfig};
+    use futures::StreamExt;
+    use tokio::io::{duplex, DuplexStream};
+
+    // a server side websocket and the client side it is connected to
+    fn pair() -> (WebSocket<DuplexStream>, WebSocket<DuplexStream>) {
+        let (server, client) = duplex(1 << 20);
+        let config = WebSocketConfig {
+            ping_interval: None,
+            ..Default::default()
+        };
+        (
+            WebSocket::from_raw(server, &[], Role::Server, config.clone()),
+            WebSocket::from_raw(client, &[], Role::Client, config),
+        )
+    }
+
+    #[tokio::test]
+    async fn broadcast_to_rooms() {
+        let hub = Hub::new();
+        let mut clients = vec![];
+        let mut members = vec![];
+
+        for room in ["a", "a", "b"] {
+            let (server, client) = pair();
+            let (_, writer) = server.split();
+            let member = hub.connect(writer);
+            member.join(room);
+            members.push(member);
+            clients.push(client);
+        }
+
+        assert_eq!(hub.room_size("a"), 2);
+        assert_eq!(hub.broadcast("a", &Message::Text("hi".to_string())), 2);
+        assert_eq!(hub.broadcast("c", &Message::Text("hi".to_string())), 0);
+
+        for client in &mut clients[..2] {
+            let message = client.next().await.unwrap().unwrap();
+            assert_eq!(message, Message::Text("hi".to_string()));
+        }
+
+        members[0].leave("a");
+        assert_eq!(hub.room_size("a"), 1);
+
+        members.clear();
+        assert!(hub.is_empty());
+        assert!(hub.rooms().is_empty());
+    }
+
+    #[tokio::test]
+    async fn disconnect_slow_consumers() {
+        let mut hub = Hub::new();
+        hub.queue_size(1).slow_consumer(SlowConsumer::Disconnect);
+
+        let (server, mut client) = pair();
+        let (_, writer) = server.split();
+        let member = hub.connect(writer);
+        member.join("room");
+
+        // the writer task doesn't get to run in between
+        let message = Message::Text("hi".to_string());
+        assert_eq!(hub.broadcast("room", &message), 1);
+        assert_eq!(hub.broadcast("room", &message), 0);
+        assert!(!member.is_connected());
+        assert_eq!(hub.room_size("room"), 0);
+
+        assert_eq!(client.next().await.unwrap().unwrap(), message);
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Close(Some(CloseFrame::new(
+                close_code::POLICY_VIOLATION,
+                "too slow"
+            )))
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::Result;
+use bytes::{Buf, Bytes};
+
+use super::frame::{Frame, ProtocolError};
+
+/// A complete websocket message, reassembled from one or more frames
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Bytes),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close(Option<CloseFrame>),
+}
+
+/// Status codes used in close frames, see https://datatracker.ietf.org/doc/html/rfc6455#section-7.4.1
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const MANDATORY_EXTENSION: u16 = 1010;
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    /// Codes which may be sent in a close frame, others (like 1005 or 1006) are only used locally
+    pub fn is_allowed(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    pub fn new(code: u16, reason: &str) -> Self {
+        CloseFrame {
+            code,
+            reason: reason.to_string(),
+        }
+    }
+
+    // An empty close payload is valid and means no status code was given
+    pub fn parse(mut payload: Bytes) -> Result<Option<Self>> {
+        match payload.len() {
+            0 => Ok(None),
+            1 => Err(ProtocolError::InvalidCloseFrame.into()),
+            _ => {
+                let code = payload.get_u16();
+                if !close_code::is_allowed(code) {
+                    return Err(ProtocolError::InvalidCloseCode(code).into());
+                }
+
+                let reason =
+                    String::from_utf8(payload.to_vec()).map_err(|_| ProtocolError::InvalidUtf8)?;
+                Ok(Some(CloseFrame { code, reason }))
+            }
+        }
+    }
+}
+
+impl Message {
+    pub fn to_frame(&self) -> Frame {
+        match self {
+            Message::Text(text) => Frame::text(text),
+            Message::Binary(data) => Frame::binary(data.clone()),
+            Message::Ping(data) => Frame::ping(data.clone()),
+            Message::Pong(data) => Frame::pong(data.clone()),
+            Message::Close(Some(close)) => Frame::close(Some(close.code), &close.reason),
+            Message::Close(None) => Frame::close(None, ""),
+        }
+    }
+}
+
+/// A message which is serialized once and can then be sent to many
+/// connections, which share the same buffer
+#[derive(Debug, Clone)]
+pub struct PreparedMessage {
+    frame: Bytes,
+    close: bool,
+}
+
+impl PreparedMessage {
+    pub fn new(message: &Message) -> Self {
+        PreparedMessage {
+            frame: message.to_frame().to_bytes().freeze(),
+            close: matches!(message, Message::Close(_)),
+        }
+    }
+
+    pub fn is_close(&self) -> bool {
+        self.close
+    }
+
+    /// The serialized (unmasked) frame
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.frame
+    }
+}
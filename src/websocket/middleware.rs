@@ -1,88 +1,100 @@
-use crate::{router::MiddlewareContext, websocket::mask::apply_mask};
+use crate::{router::MiddlewareContext, ServerError};
 use anyhow::Result;
-use bytes::{BufMut, BytesMut};
+use futures::StreamExt;
 use parking_lot::MutexGuard;
-use sha1::{Digest, Sha1};
-use std::{io::ErrorKind, str::from_utf8};
-use tokio::io::AsyncWriteExt;
 
-// use tokio::io::{AsyncReadExt};
-// use std::ops::DerefMut;
-
-use super::frame::FrameHeader;
+use super::{
+    config::WebSocketConfig,
+    deflate::negotiate_deflate,
+    handshake::{self, HandshakeError, WEBSOCKET_VERSION},
+    stream::{Role, WebSocket},
+};
 
 pub async fn accept_websocket<'a>(ctx: &mut MutexGuard<'a, MiddlewareContext>) -> Result<()> {
-    println!("got incoming websocket connection");
-
-    let connection = match ctx.request.headers.get_str("Connection") {
-        Err(_) => return Ok(()),
-        Ok(v) => v,
-    };
-
-    let upgrade = match ctx.request.headers.get_str("Upgrade") {
-        Err(_) => return Ok(()),
-        Ok(v) => v,
-    };
+    accept_websocket_with_config(ctx, WebSocketConfig::default()).await
+}
 
-    if connection != "Upgrade" || upgrade != "websocket" {
+// Accepts websocket connections and prints all incoming messages,
+// other requests are passed on to the next middleware
+pub async fn accept_websocket_with_config<'a>(
+    ctx: &mut MutexGuard<'a, MiddlewareContext>,
+    config: WebSocketConfig,
+) -> Result<()> {
+    if !handshake::is_upgrade_request(&ctx.request) {
         return Ok(());
     }
 
-    let version = ctx.request.headers.get_str("Sec-WebSocket-Version")?;
-    let key = ctx.request.headers.get_str("Sec-WebSocket-Key")?;
+    println!("got incoming websocket connection");
+    let mut websocket = upgrade_websocket(ctx, config).await?;
 
-    println!("websocket client connected with version {}", version);
+    while let Some(message) = websocket.next().await {
+        println!("got websocket message: {:?}", message?);
+    }
 
-    let mut hasher = Sha1::new();
-    hasher.update(key);
-    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
-    let result = hasher.finalize();
-    let accept = base64::encode(result);
+    Ok(())
+}
 
-    // ctx.set_raw(true);
-    let mut resp = BytesMut::new();
-    resp.put_slice(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: ");
+/// Completes the websocket handshake and takes over the connection.
+///
+/// The returned [`WebSocket`] can be moved into a separate task, no further
+/// middlewares are called and no http response is written for this request.
+/// Invalid handshakes are answered with `400 Bad Request` (or `426 Upgrade Required`
+/// for unsupported versions and `403 Forbidden` for disallowed origins).
+pub async fn upgrade_websocket(
+    ctx: &mut MiddlewareContext,
+    config: WebSocketConfig,
+) -> Result<WebSocket> {
+    let accept = match handshake::validate_request(&ctx.request) {
+        Ok(accept) => accept,
+        Err(e) => return Err(reject(ctx, e)),
+    };
 
-    ctx.socket.write(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: ").await?;
-    ctx.socket.write(&accept.as_bytes()).await?;
-    ctx.socket.write(b"\r\n\r\n").await?;
+    if let Err(e) = handshake::validate_origin(&ctx.request, &config) {
+        return Err(reject(ctx, e));
+    }
 
-    loop {
-        ctx.socket.readable().await?;
-        let mut buf = BytesMut::with_capacity(65536);
+    let protocol = handshake::negotiate_protocol(&ctx.request, &config);
+    let deflate = config.deflate.as_ref().and_then(|deflate| {
+        let offers = ctx
+            .request
+            .headers
+            .get_str("Sec-WebSocket-Extensions")
+            .ok()?;
+        negotiate_deflate(&offers, deflate).map(|params| (params, deflate.level, deflate.threshold))
+    });
+
+    ctx.response.set_header("Upgrade", "websocket");
+    ctx.response.set_header("Connection", "Upgrade");
+    ctx.response.set_header("Sec-WebSocket-Accept", &accept);
+    if let Some(protocol) = protocol.as_ref() {
+        ctx.response.set_header("Sec-WebSocket-Protocol", protocol);
+    }
+    if let Some((params, _, _)) = deflate.as_ref() {
+        ctx.response
+            .set_header("Sec-WebSocket-Extensions", &params.to_header());
+    }
 
-        match ctx.socket.try_read_buf(&mut buf) {
-            Ok(0) => break,
-            Ok(n) => {
-                println!("read {} bytes", n);
-            }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                continue;
-            }
-            Err(e) => {
-                return Err(e.into());
-            } // https://github.com/1tgr/rust-websocket-lite/blob/master/websocket-codec/src/frame.rs
-        };
+    // the client might have sent frames right after the handshake
+    let (socket, buffered) = ctx.upgrade_connection().await?.into_parts();
+    let mut websocket = WebSocket::from_raw(socket, &buffered, Role::Server, config);
+    websocket.set_protocol(protocol);
+    if let Some((params, level, threshold)) = deflate {
+        websocket.set_deflate(&params, level, threshold);
+    }
+    Ok(websocket)
+}
 
-        let mut buf = buf.freeze();
-        let header = FrameHeader::from_bytes(&mut buf);
-        match header {
-            Ok(header) => {
-                println!("got websocket data:\n  header: {:?}", header);
-                if let Some(mask) = header.mask {
-                    let mut data = buf.to_vec();
-                    apply_mask(&mut data, mask);
+// prepares the error response and ends the request
+fn reject(ctx: &mut MiddlewareContext, error: HandshakeError) -> anyhow::Error {
+    ctx.response.clear();
+    ctx.response.status_code(error.status_code());
+    ctx.response.write(error.to_string().as_bytes());
 
-                    if let Some(data) = from_utf8(&data).ok() {
-                        println!("  data: {:?}", data);
-                    }
-                }
-            }
-            Err(e) => {
-                println!("got invalid data: {:?}", e);
-            }
-        }
+    if error == HandshakeError::Version {
+        ctx.response
+            .set_header("Sec-WebSocket-Version", WEBSOCKET_VERSION);
     }
 
-    Ok(())
+    ctx.end();
+    anyhow::Error::new(error).context(ServerError::Rejected)
 }
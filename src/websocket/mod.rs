@@ -1,8 +1,32 @@
+mod client;
+mod config;
+mod deflate;
 mod frame;
+mod handshake;
+mod hub;
 mod mask;
+mod message;
 mod middleware;
+mod reader;
+mod stream;
 
-pub use middleware::accept_websocket;
+pub use client::{connect, connect_with_config, ConnectError};
+pub use config::{OriginPolicy, WebSocketConfig};
+pub use deflate::{
+    deflate_offer, negotiate_deflate, parse_deflate_response, DeflateConfig, DeflateParams,
+    Deflater, Inflater,
+};
+pub use frame::{DataLength, Frame, FrameHeader, OpCode, ProtocolError};
+pub use handshake::{
+    accept_key, is_upgrade_request, negotiate_protocol, validate_origin, validate_request,
+    HandshakeError,
+};
+pub use hub::{Hub, Member, SlowConsumer};
+pub use mask::apply_mask;
+pub use message::{close_code, CloseFrame, Message, PreparedMessage};
+pub use middleware::{accept_websocket, accept_websocket_with_config, upgrade_websocket};
+pub use reader::FrameReader;
+pub use stream::{Role, WebSocket, WebSocketReader, WebSocketWriter};
 
 // https://developer.mozilla.org/en-US/docs/Web/API/WebSockets_API/Writing_WebSocket_servers
 // https://datatracker.ietf.org/doc/html/rfc6455
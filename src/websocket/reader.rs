@@ -0,0 +1,462 @@
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+
+use super::{
+    config::DEFAULT_MAX_MESSAGE_SIZE,
+    deflate::Inflater,
+    frame::{Frame, FrameHeader, OpCode, ProtocolError},
+    mask::apply_mask,
+    message::{CloseFrame, Message},
+};
+
+const READ_BUFFER_SIZE: usize = 65536;
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+/// Buffers incoming data until complete frames are available and
+/// reassembles fragmented messages from their continuation frames.
+///
+/// Frames violating the protocol fail with a [`ProtocolError`].
+pub struct FrameReader {
+    buf: BytesMut,
+    fragments: Option<Fragments>,
+    // set once permessage-deflate was negotiated
+    inflater: Option<Inflater>,
+    require_mask: Option<bool>,
+    max_message_size: usize,
+}
+
+// a fragmented message which is still being received
+struct Fragments {
+    opcode: OpCode,
+    compressed: bool,
+    data: BytesMut,
+    // length of the prefix of `data` which is known to be valid utf8
+    valid_utf8: usize,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self {
+            buf: BytesMut::with_capacity(READ_BUFFER_SIZE),
+            fragments: None,
+            inflater: None,
+            require_mask: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Decompresses messages with the `rsv1` bit set from now on
+    pub fn set_inflater(&mut self, inflater: Inflater) {
+        self.inflater = Some(inflater);
+    }
+
+    /// Requires all frames to be masked (`true`, frames sent by clients) or
+    /// unmasked (`false`, frames sent by servers)
+    pub fn require_mask(&mut self, masked: bool) -> &mut Self {
+        self.require_mask = Some(masked);
+        self
+    }
+
+    /// Fails on messages larger than `max_size` bytes (after decompression), defaults to 64 MiB.
+    /// `None` removes the limit.
+    pub fn max_message_size(&mut self, max_size: Option<usize>) -> &mut Self {
+        self.max_message_size = max_size.unwrap_or(usize::MAX);
+        self
+    }
+
+    /// The buffer new data from the socket should be read into
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        if self.buf.capacity() - self.buf.len() < READ_BUFFER_SIZE / 4 {
+            self.buf.reserve(READ_BUFFER_SIZE);
+        }
+        &mut self.buf
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame with an unmasked payload,
+    /// or `None` if more data has to be read first.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        let header_length = match FrameReader::header_length(&self.buf) {
+            Some(length) if length <= self.buf.len() => length,
+            _ => return Ok(None),
+        };
+
+        let header =
+            FrameHeader::from_bytes(&mut Bytes::copy_from_slice(&self.buf[..header_length]))?;
+        self.validate_header(&header)?;
+
+        // checked before buffering the payload, which is only read as it arrives,
+        // so announcing a large frame doesn't make us allocate its size up front
+        let received = self.fragments.as_ref().map_or(0, |f| f.data.len());
+        let payload_length = usize::try_from(header.data_length.length())
+            .ok()
+            .filter(|length| length.saturating_add(received) <= self.max_message_size)
+            .ok_or(ProtocolError::MessageTooBig(self.max_message_size))?;
+        let frame_length = header_length
+            .checked_add(payload_length)
+            .ok_or_else(|| anyhow!("payload: length too large"))?;
+
+        if self.buf.len() < frame_length {
+            return Ok(None);
+        }
+
+        let _ = self.buf.split_to(header_length);
+        let mut payload = self.buf.split_to(payload_length);
+        if let Some(mask) = header.mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Some(Frame {
+            header,
+            payload: payload.freeze(),
+        }))
+    }
+
+    /// Returns the next complete message, or `None` if more data has to be read first.
+    /// Control frames are returned right away, even in the middle of a fragmented message.
+    pub fn next_message(&mut self) -> Result<Option<Message>> {
+        while let Some(frame) = self.next_frame()? {
+            if let Some(message) = self.push_frame(frame)? {
+                return Ok(Some(message));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc6455#section-5.2
+    fn validate_header(&self, header: &FrameHeader) -> Result<(), ProtocolError> {
+        if let OpCode::Reserved(opcode) = header.opcode {
+            return Err(ProtocolError::UnknownOpCode(opcode));
+        }
+
+        // rsv1 marks compressed messages, so it's only allowed on their first frame
+        let compressed = header.rsv1
+            && self.inflater.is_some()
+            && matches!(header.opcode, OpCode::Text | OpCode::Binary);
+        if header.rsv1 && !compressed || header.rsv2 || header.rsv3 {
+            return Err(ProtocolError::ReservedBits);
+        }
+
+        match self.require_mask {
+            Some(true) if header.mask.is_none() => return Err(ProtocolError::UnmaskedFrame),
+            Some(false) if header.mask.is_some() => return Err(ProtocolError::MaskedFrame),
+            _ => {}
+        }
+
+        if header.opcode.is_control() {
+            if !header.fin {
+                return Err(ProtocolError::FragmentedControlFrame);
+            }
+            if header.data_length.length() > MAX_CONTROL_PAYLOAD {
+                return Err(ProtocolError::ControlFrameTooLarge);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_frame(&mut self, frame: Frame) -> Result<Option<Message>> {
+        let Frame { header, payload } = frame;
+
+        match header.opcode {
+            OpCode::Ping => Ok(Some(Message::Ping(payload))),
+            OpCode::Pong => Ok(Some(Message::Pong(payload))),
+            OpCode::Close => Ok(Some(Message::Close(CloseFrame::parse(payload)?))),
+            OpCode::Text | OpCode::Binary => {
+                if self.fragments.is_some() {
+                    return Err(ProtocolError::ExpectedContinuation.into());
+                }
+
+                if header.fin {
+                    return self
+                        .build_message(header.opcode, header.rsv1, payload)
+                        .map(Some);
+                }
+
+                let mut fragments = Fragments {
+                    opcode: header.opcode,
+                    compressed: header.rsv1,
+                    data: BytesMut::from(&payload[..]),
+                    valid_utf8: 0,
+                };
+                fragments.validate_utf8()?;
+                self.fragments = Some(fragments);
+                Ok(None)
+            }
+            OpCode::Continuation => {
+                let fragments = self
+                    .fragments
+                    .as_mut()
+                    .ok_or(ProtocolError::UnexpectedContinuation)?;
+                fragments.data.extend_from_slice(&payload);
+                fragments.validate_utf8()?;
+
+                if !header.fin {
+                    return Ok(None);
+                }
+
+                let fragments = self.fragments.take().expect("fragments to exist");
+                self.build_message(
+                    fragments.opcode,
+                    fragments.compressed,
+                    fragments.data.freeze(),
+                )
+                .map(Some)
+            }
+            OpCode::Reserved(opcode) => Err(ProtocolError::UnknownOpCode(opcode).into()),
+        }
+    }
+
+    fn build_message(&mut self, opcode: OpCode, compressed: bool, data: Bytes) -> Result<Message> {
+        let data = match self.inflater.as_mut() {
+            Some(inflater) if compressed => inflater.decompress(&data, self.max_message_size)?,
+            _ => data,
+        };
+
+        match opcode {
+            OpCode::Text => Ok(Message::Text(
+                String::from_utf8(data.to_vec()).map_err(|_| ProtocolError::InvalidUtf8)?,
+            )),
+            _ => Ok(Message::Binary(data)),
+        }
+    }
+
+    // length of the frame header, if enough bytes are available to know it
+    fn header_length(buf: &[u8]) -> Option<usize> {
+        if buf.len() < 2 {
+            return None;
+        }
+
+        let masked = buf[1] & 0x80 != 0;
+        let length = match buf[1] & 0x7f {
+            127 => 10,
+            126 => 4,
+            _ => 2,
+        };
+
+        Some(if masked { length + 4 } else { length })
+    }
+}
+
+impl Fragments {
+    // fails as soon as the received text can't become valid utf8 anymore,
+    // a character might still be incomplete at the end of the data
+    fn validate_utf8(&mut self) -> Result<(), ProtocolError> {
+        if self.opcode != OpCode::Text || self.compressed {
+            return Ok(());
+        }
+
+        match std::str::from_utf8(&self.data[self.valid_utf8..]) {
+            Ok(_) => self.valid_utf8 = self.data.len(),
+            Err(e) if e.error_len().is_none() => self.valid_utf8 += e.valid_up_to(),
+            Err(_) => return Err(ProtocolError::InvalidUtf8),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(frame: &mut Frame) -> BytesMut {
+        frame.mask([1, 2, 3, 4]).to_bytes()
+    }
+
+    #[test]
+    fn buffer_frames_across_reads() {
+        let bytes = masked(&mut Frame::text("Hello World"));
+        let mut reader = FrameReader::new();
+
+        for chunk in bytes.chunks(3) {
+            assert_eq!(reader.next_message().unwrap(), None);
+            reader.extend(chunk);
+        }
+
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(Message::Text("Hello World".to_string()))
+        );
+        assert_eq!(reader.next_message().unwrap(), None);
+    }
+
+    #[test]
+    fn read_multiple_frames_at_once() {
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(&mut Frame::text("a")));
+        reader.extend(&masked(&mut Frame::binary(Bytes::from_static(b"b"))));
+        reader.extend(&masked(&mut Frame::text("c"))[..3]);
+
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(Message::Text("a".to_string()))
+        );
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(Message::Binary(Bytes::from_static(b"b")))
+        );
+        assert_eq!(reader.next_message().unwrap(), None);
+    }
+
+    #[test]
+    fn reassemble_fragmented_messages() {
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(Frame::text("Hel").fin(false)));
+        reader.extend(&masked(&mut Frame::ping(Bytes::from_static(b"ping"))));
+        reader.extend(&masked(
+            Frame::new(OpCode::Continuation, Bytes::from_static(b"lo ")).fin(false),
+        ));
+        reader.extend(&masked(&mut Frame::new(
+            OpCode::Continuation,
+            Bytes::from_static(b"World"),
+        )));
+
+        // control frames can be interleaved with fragments
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(Message::Ping(Bytes::from_static(b"ping")))
+        );
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(Message::Text("Hello World".to_string()))
+        );
+    }
+
+    #[test]
+    fn do_not_accept_unexpected_continuation() {
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(&mut Frame::new(
+            OpCode::Continuation,
+            Bytes::from_static(b"x"),
+        )));
+        reader.next_message().expect_err("reading message");
+    }
+
+    fn protocol_error(reader: &mut FrameReader) -> ProtocolError {
+        let err = reader.next_message().expect_err("reading message");
+        err.downcast::<ProtocolError>().expect("protocol error")
+    }
+
+    #[test]
+    fn validate_reserved_bits_and_opcodes() {
+        let mut frame = Frame::text("x");
+        frame.header.rsv2 = true;
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(&mut frame));
+        assert_eq!(protocol_error(&mut reader), ProtocolError::ReservedBits);
+
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(&mut Frame::new(OpCode::Reserved(3), Bytes::new())));
+        assert_eq!(protocol_error(&mut reader), ProtocolError::UnknownOpCode(3));
+    }
+
+    #[test]
+    fn validate_control_frames() {
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(Frame::ping(Bytes::new()).fin(false)));
+        assert_eq!(
+            protocol_error(&mut reader),
+            ProtocolError::FragmentedControlFrame
+        );
+
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(&mut Frame::ping(Bytes::from(vec![0; 126]))));
+        assert_eq!(
+            protocol_error(&mut reader),
+            ProtocolError::ControlFrameTooLarge
+        );
+
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(&mut Frame::close(Some(1005), "")));
+        assert_eq!(
+            protocol_error(&mut reader),
+            ProtocolError::InvalidCloseCode(1005)
+        );
+    }
+
+    #[test]
+    fn require_masked_frames() {
+        let mut reader = FrameReader::new();
+        reader.require_mask(true);
+        reader.extend(&Frame::text("x").to_bytes());
+        assert_eq!(protocol_error(&mut reader), ProtocolError::UnmaskedFrame);
+
+        let mut reader = FrameReader::new();
+        reader.require_mask(false);
+        reader.extend(&masked(&mut Frame::text("x")));
+        assert_eq!(protocol_error(&mut reader), ProtocolError::MaskedFrame);
+    }
+
+    #[test]
+    fn validate_utf8_across_fragments() {
+        // "€" split across two frames is valid
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(
+            Frame::new(OpCode::Text, Bytes::from_static(b"\xe2\x82")).fin(false),
+        ));
+        reader.extend(&masked(&mut Frame::new(
+            OpCode::Continuation,
+            Bytes::from_static(b"\xac"),
+        )));
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(Message::Text("€".to_string()))
+        );
+
+        // invalid text fails before the message is complete
+        let mut reader = FrameReader::new();
+        reader.extend(&masked(
+            Frame::new(OpCode::Text, Bytes::from_static(b"ok\xff")).fin(false),
+        ));
+        assert_eq!(protocol_error(&mut reader), ProtocolError::InvalidUtf8);
+        assert_eq!(ProtocolError::InvalidUtf8.close_code(), 1007);
+    }
+
+    #[test]
+    fn limit_message_size() {
+        let mut reader = FrameReader::new();
+        assert_eq!(reader.max_message_size, DEFAULT_MAX_MESSAGE_SIZE);
+        reader.max_message_size(Some(10));
+        reader.extend(&masked(Frame::text("Hello").fin(false)));
+        reader.extend(&masked(&mut Frame::new(
+            OpCode::Continuation,
+            Bytes::from_static(b" World"),
+        )));
+
+        let err = protocol_error(&mut reader);
+        assert_eq!(err, ProtocolError::MessageTooBig(10));
+        assert_eq!(err.close_code(), 1009);
+
+        // the length is checked before the payload is received
+        let mut reader = FrameReader::new();
+        reader.max_message_size(Some(10));
+        reader.extend(&masked(&mut Frame::binary(Bytes::from(vec![0; 100])))[..10]);
+        assert_eq!(
+            protocol_error(&mut reader),
+            ProtocolError::MessageTooBig(10)
+        );
+    }
+
+    #[test]
+    fn grow_buffer_as_payload_arrives() {
+        let mut reader = FrameReader::new();
+        let mut header = FrameHeader::new(OpCode::Binary, 32 << 20);
+        header.mask = Some([1, 2, 3, 4]);
+        reader.extend(&header.to_bytes());
+
+        assert!(reader.next_frame().unwrap().is_none());
+        reader.buffer_mut();
+        assert!(reader.buf.capacity() <= 2 * READ_BUFFER_SIZE);
+    }
+}
@@ -0,0 +1,709 @@
+use anyhow::{anyhow, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{
+    future, ready,
+    task::{waker, ArcWake, AtomicWaker},
+    Sink, Stream,
+};
+use parking_lot::Mutex;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant, Sleep},
+};
+
+use crate::transport::BoxedTransport;
+
+use super::{
+    config::WebSocketConfig,
+    deflate::{DeflateParams, Deflater, Inflater},
+    frame::{Frame, ProtocolError},
+    message::{close_code, CloseFrame, Message, PreparedMessage},
+    reader::FrameReader,
+};
+
+const READ_CHUNK_SIZE: usize = 16384;
+// `poll_ready` flushes the write buffer once it grows beyond this
+const WRITE_BUFFER_SIZE: usize = 65536;
+// how long the close frame sent after an error may take to be written
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which side of the connection we are, clients have to mask their frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Open,
+    // we sent a close frame and wait for the peer to answer
+    CloseSent,
+    // the peer sent a close frame, the socket is shut down once our answer is written
+    CloseReceived,
+    // the connection failed, the error is returned once our close frame is written
+    Failed,
+    Closed,
+}
+
+struct Failure {
+    error: anyhow::Error,
+    timeout: Pin<Box<Sleep>>,
+}
+
+// The stream only keeps the waker of the last write that returned `Pending`. Both halves
+// write to it, so each registers its own waker here and writes are polled with a waker that
+// wakes both, otherwise the reader flushing a pong would leave a parked writer hanging.
+#[derive(Default)]
+struct WriteWakers {
+    reader: AtomicWaker,
+    writer: AtomicWaker,
+}
+
+impl ArcWake for WriteWakers {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.reader.wake();
+        arc_self.writer.wake();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Half {
+    Reader,
+    Writer,
+}
+
+/// A websocket connection which yields incoming messages as a `Stream` and
+/// sends outgoing messages through its `Sink` implementation.
+///
+/// Pings are answered automatically and the close handshake is completed
+/// once a close message is received from the peer.
+pub struct WebSocket<S = BoxedTransport> {
+    stream: S,
+    reader: FrameReader,
+    read_chunk: Vec<u8>,
+    write_buf: BytesMut,
+    write_wakers: Arc<WriteWakers>,
+    write_waker: Waker,
+    heartbeat: Option<Heartbeat>,
+    state: State,
+    failure: Option<Failure>,
+    role: Role,
+    protocol: Option<String>,
+    deflater: Option<Deflater>,
+}
+
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    next_ping: Instant,
+    // set while we are waiting for a pong
+    pong_deadline: Option<Instant>,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<S> WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wraps a stream on which the handshake has already been completed.
+    /// `buffered` contains data that was read from the stream past the handshake.
+    pub fn from_raw(stream: S, buffered: &[u8], role: Role, config: WebSocketConfig) -> Self {
+        let mut reader = FrameReader::new();
+        reader
+            .require_mask(role == Role::Server)
+            .max_message_size(config.max_message_size);
+        reader.extend(buffered);
+
+        let heartbeat = config.ping_interval.map(|interval| {
+            let next_ping = Instant::now() + interval;
+            Heartbeat {
+                interval,
+                timeout: config.ping_timeout,
+                next_ping,
+                pong_deadline: None,
+                sleep: Box::pin(time::sleep_until(next_ping)),
+            }
+        });
+
+        let write_wakers = Arc::new(WriteWakers::default());
+        Self {
+            stream,
+            reader,
+            read_chunk: vec![0; READ_CHUNK_SIZE],
+            write_buf: BytesMut::new(),
+            write_waker: waker(write_wakers.clone()),
+            write_wakers,
+            heartbeat,
+            state: State::Open,
+            failure: None,
+            role,
+            protocol: None,
+            deflater: None,
+        }
+    }
+
+    /// The subprotocol which was agreed on during the handshake
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub(crate) fn set_protocol(&mut self, protocol: Option<String>) {
+        self.protocol = protocol;
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    // enables permessage-deflate with the parameters from the handshake
+    pub(crate) fn set_deflate(&mut self, params: &DeflateParams, level: u32, threshold: usize) {
+        let (ours, theirs) = match self.role {
+            Role::Server => (
+                params.server_no_context_takeover,
+                params.client_no_context_takeover,
+            ),
+            Role::Client => (
+                params.client_no_context_takeover,
+                params.server_no_context_takeover,
+            ),
+        };
+
+        self.deflater = Some(Deflater::new(level, threshold, ours));
+        self.reader.set_inflater(Inflater::new(theirs));
+    }
+
+    /// Splits the connection into a reader and a writer half, which can be moved into separate tasks
+    pub fn split(self) -> (WebSocketReader<S>, WebSocketWriter<S>) {
+        let inner = Arc::new(Mutex::new(self));
+        (
+            WebSocketReader {
+                inner: inner.clone(),
+            },
+            WebSocketWriter { inner },
+        )
+    }
+
+    fn queue(&mut self, mut frame: Frame) {
+        // clients use a new random mask for every frame
+        if self.role == Role::Client {
+            frame.mask(rand::random());
+        }
+        self.write_buf.extend_from_slice(&frame.to_bytes());
+    }
+
+    // prepared messages skip compression and masking, so they can only be sent by servers
+    fn queue_prepared(&mut self, message: &PreparedMessage) -> Result<()> {
+        if self.state != State::Open {
+            return Err(anyhow!("websocket: connection is closed"));
+        }
+
+        if self.role != Role::Server {
+            return Err(anyhow!("websocket: clients can't send prepared messages"));
+        }
+
+        if message.is_close() {
+            self.state = State::CloseSent;
+        }
+
+        self.write_buf.extend_from_slice(message.as_bytes());
+        Ok(())
+    }
+
+    fn queue_close(&mut self, close: Option<CloseFrame>) {
+        let frame = match close {
+            Some(close) => Frame::close(Some(close.code), &close.reason),
+            None => Frame::close(None, ""),
+        };
+        self.queue(frame);
+    }
+
+    fn register_write(&self, cx: &Context<'_>, half: Half) {
+        match half {
+            Half::Reader => self.write_wakers.reader.register(cx.waker()),
+            Half::Writer => self.write_wakers.writer.register(cx.waker()),
+        }
+    }
+
+    // writes all queued frames to the stream
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>, half: Half) -> Poll<Result<()>> {
+        self.register_write(cx, half);
+        let mut cx = Context::from_waker(&self.write_waker);
+
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(&mut cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(anyhow!("websocket: failed to write frame")));
+            }
+            self.write_buf.advance(n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush_stream(&mut self, cx: &mut Context<'_>, half: Half) -> Poll<Result<()>> {
+        ready!(self.poll_write_buf(cx, half))?;
+        let mut cx = Context::from_waker(&self.write_waker);
+        ready!(Pin::new(&mut self.stream).poll_flush(&mut cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown_stream(&mut self, cx: &mut Context<'_>, half: Half) -> Poll<Result<()>> {
+        ready!(self.poll_write_buf(cx, half))?;
+        let mut cx = Context::from_waker(&self.write_waker);
+        ready!(Pin::new(&mut self.stream).poll_shutdown(&mut cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_more(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut buf = ReadBuf::new(&mut self.read_chunk);
+        ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+
+        let read = buf.filled();
+        self.reader.extend(read);
+        Poll::Ready(Ok(read.len()))
+    }
+
+    // sends pings and fails once the peer didn't answer the last one in time
+    fn poll_heartbeat(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let heartbeat = match self.heartbeat.as_mut() {
+            Some(heartbeat) => heartbeat,
+            None => return Poll::Pending,
+        };
+
+        ready!(heartbeat.sleep.as_mut().poll(cx));
+        let now = Instant::now();
+
+        if matches!(heartbeat.pong_deadline, Some(deadline) if deadline <= now) {
+            return Poll::Ready(Err(anyhow!("websocket: ping timeout")));
+        }
+
+        if heartbeat.next_ping <= now {
+            heartbeat.next_ping = now + heartbeat.interval;
+            heartbeat
+                .pong_deadline
+                .get_or_insert(now + heartbeat.timeout);
+            self.queue(Frame::ping(Bytes::new()));
+        }
+
+        let heartbeat = self.heartbeat.as_mut().expect("heartbeat to exist");
+        let deadline = match heartbeat.pong_deadline {
+            Some(deadline) => deadline.min(heartbeat.next_ping),
+            None => heartbeat.next_ping,
+        };
+        heartbeat.sleep.as_mut().reset(deadline);
+
+        Poll::Ready(Ok(()))
+    }
+
+    // handles control frames, the message is still passed on to the user
+    fn on_message(&mut self, message: &Message) {
+        match message {
+            Message::Ping(data) => {
+                if self.state == State::Open {
+                    self.queue(Frame::pong(data.clone()));
+                }
+            }
+            Message::Pong(_) => {
+                if let Some(heartbeat) = self.heartbeat.as_mut() {
+                    heartbeat.pong_deadline = None;
+                }
+            }
+            Message::Close(close) => match self.state {
+                State::Open => {
+                    // echo the status code to complete the close handshake
+                    let code = close
+                        .as_ref()
+                        .map_or(close_code::NORMAL, |close| close.code);
+                    self.queue_close(Some(CloseFrame::new(code, "")));
+                    self.state = State::CloseReceived;
+                }
+                State::CloseSent => self.state = State::Closed,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    // flushes pending frames and shuts down the socket after the close handshake
+    fn poll_finish_close(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.poll_shutdown_stream(cx, Half::Reader))?;
+        self.state = State::Closed;
+        Poll::Ready(Ok(()))
+    }
+
+    // sends a close frame with `code` before the error is returned
+    fn fail(&mut self, error: anyhow::Error, code: u16, reason: &str) {
+        if self.state == State::Open {
+            self.queue_close(Some(CloseFrame::new(code, reason)));
+        }
+        self.state = State::Failed;
+        self.failure = Some(Failure {
+            error,
+            timeout: Box::pin(time::sleep(CLOSE_TIMEOUT)),
+        });
+    }
+
+    // waits until the close frame is written (or it took too long) and returns the error
+    fn poll_failure(&mut self, cx: &mut Context<'_>) -> Poll<anyhow::Error> {
+        let flushed = self.poll_finish_close(cx).is_ready();
+        let failure = self.failure.as_mut().expect("failure to exist");
+        if !flushed && failure.timeout.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        self.state = State::Closed;
+        Poll::Ready(self.failure.take().expect("failure to exist").error)
+    }
+}
+
+impl<S> Stream for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            match this.state {
+                State::Closed => return Poll::Ready(None),
+                State::CloseReceived => {
+                    ready!(this.poll_finish_close(cx))?;
+                    return Poll::Ready(None);
+                }
+                State::Failed => return this.poll_failure(cx).map(|e| Some(Err(e))),
+                _ => {}
+            }
+
+            // queued pongs are written whenever possible, without blocking the reader
+            if let Poll::Ready(Err(e)) = this.poll_write_buf(cx, Half::Reader) {
+                this.state = State::Closed;
+                return Poll::Ready(Some(Err(e)));
+            }
+
+            match this.reader.next_message() {
+                Ok(Some(message)) => {
+                    this.on_message(&message);
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    // let the peer know why we are closing the connection
+                    if let Some(code) = e.downcast_ref::<ProtocolError>().map(|e| e.close_code()) {
+                        this.fail(e, code, "");
+                        continue;
+                    }
+                    this.state = State::Closed;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+
+            if let Poll::Ready(result) = this.poll_heartbeat(cx) {
+                if let Err(e) = result {
+                    // the peer is unresponsive, so we don't wait for the close handshake
+                    this.fail(e, close_code::GOING_AWAY, "ping timeout");
+                }
+                continue;
+            }
+
+            match ready!(this.poll_read_more(cx)) {
+                Ok(0) => {
+                    this.state = State::Closed;
+                    return Poll::Ready(None);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    this.state = State::Closed;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
+
+impl<S> Sink<Message> for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = anyhow::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.state != State::Open {
+            return Poll::Ready(Err(anyhow!("websocket: connection is closed")));
+        }
+
+        if self.write_buf.len() >= WRITE_BUFFER_SIZE {
+            ready!(self.poll_write_buf(cx, Half::Writer))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<()> {
+        if self.state != State::Open {
+            return Err(anyhow!("websocket: connection is closed"));
+        }
+
+        if let Message::Close(_) = message {
+            self.state = State::CloseSent;
+        }
+
+        let mut frame = message.to_frame();
+        if let Some(deflater) = self.deflater.as_mut() {
+            if !frame.header.opcode.is_control() && deflater.should_compress(&frame.payload) {
+                frame = Frame::new(frame.header.opcode, deflater.compress(&frame.payload)?);
+                frame.header.rsv1 = true;
+            }
+        }
+
+        self.queue(frame);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush_stream(cx, Half::Writer)
+    }
+
+    // sends a close frame (unless one was sent already) and shuts down the writing side,
+    // the peer's answer can still be read from the stream afterwards
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.state == State::Open {
+            self.queue_close(Some(CloseFrame::new(close_code::NORMAL, "")));
+            self.state = State::CloseSent;
+        }
+
+        self.poll_shutdown_stream(cx, Half::Writer)
+    }
+}
+
+/// The reading half of a [`WebSocket`], created by [`WebSocket::split`]
+pub struct WebSocketReader<S = BoxedTransport> {
+    inner: Arc<Mutex<WebSocket<S>>>,
+}
+
+/// The writing half of a [`WebSocket`], created by [`WebSocket::split`]
+pub struct WebSocketWriter<S = BoxedTransport> {
+    inner: Arc<Mutex<WebSocket<S>>>,
+}
+
+impl<S> WebSocketWriter<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Sends messages which were serialized once for many connections, see [`PreparedMessage`]
+    pub async fn send_prepared(
+        &mut self,
+        messages: impl IntoIterator<Item = PreparedMessage>,
+    ) -> Result<()> {
+        for message in messages {
+            future::poll_fn(|cx| Pin::new(&mut *self.inner.lock()).poll_ready(cx)).await?;
+            self.inner.lock().queue_prepared(&message)?;
+        }
+
+        future::poll_fn(|cx| Pin::new(&mut *self.inner.lock()).poll_flush(cx)).await
+    }
+}
+
+impl<S> Stream for WebSocketReader<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut *self.inner.lock()).poll_next(cx)
+    }
+}
+
+impl<S> Sink<Message> for WebSocketWriter<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = anyhow::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut *self.inner.lock()).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<()> {
+        Pin::new(&mut *self.inner.lock()).start_send(message)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut *self.inner.lock()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut *self.inner.lock()).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    fn server(config: WebSocketConfig) -> (WebSocket<DuplexStream>, DuplexStream) {
+        let (server, client) = duplex(1024);
+        (
+            WebSocket::from_raw(server, &[], Role::Server, config),
+            client,
+        )
+    }
+
+    fn without_heartbeat() -> WebSocketConfig {
+        WebSocketConfig {
+            ping_interval: None,
+            ..WebSocketConfig::default()
+        }
+    }
+
+    async fn send_frame(client: &mut DuplexStream, frame: &mut Frame) {
+        let bytes = frame.mask([1, 2, 3, 4]).to_bytes();
+        client.write_all(&bytes).await.unwrap();
+    }
+
+    async fn read_exact(client: &mut DuplexStream, length: usize) -> Vec<u8> {
+        let mut buf = vec![0; length];
+        client.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn answer_pings() {
+        let (mut ws, mut client) = server(without_heartbeat());
+        send_frame(&mut client, &mut Frame::ping(Bytes::from_static(b"hi"))).await;
+
+        // the ping is passed on and the pong is written on the next poll
+        assert!(matches!(ws.next().await, Some(Ok(Message::Ping(data))) if data == "hi"));
+        let timeout = Duration::from_millis(10);
+        assert!(time::timeout(timeout, ws.next()).await.is_err());
+        assert_eq!(read_exact(&mut client, 4).await, [0x8a, 0x02, b'h', b'i']);
+    }
+
+    #[tokio::test]
+    async fn close_after_ping_timeout() {
+        let (mut ws, mut client) = server(WebSocketConfig {
+            ping_interval: Some(Duration::from_millis(10)),
+            ping_timeout: Duration::from_millis(5),
+            ..WebSocketConfig::default()
+        });
+
+        // the client never answers the ping
+        let err = ws.next().await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "websocket: ping timeout");
+        assert!(ws.next().await.is_none());
+
+        assert_eq!(read_exact(&mut client, 2).await, [0x89, 0x00]);
+        let close = read_exact(&mut client, 16).await;
+        assert_eq!(close[..4], [0x88, 14, 0x03, 0xe9]);
+        assert_eq!(&close[4..], b"ping timeout");
+    }
+
+    #[tokio::test]
+    async fn answer_close_frames() {
+        let (mut ws, mut client) = server(without_heartbeat());
+        send_frame(
+            &mut client,
+            &mut Frame::close(Some(close_code::NORMAL), "bye"),
+        )
+        .await;
+
+        assert!(matches!(
+            ws.next().await,
+            Some(Ok(Message::Close(Some(CloseFrame { code: 1000, .. }))))
+        ));
+        assert!(ws.next().await.is_none());
+
+        // the status code is echoed and the socket is shut down afterwards
+        let mut answer = vec![];
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, [0x88, 0x02, 0x03, 0xe8]);
+    }
+
+    #[tokio::test]
+    async fn send_close_frame_before_reporting_errors() {
+        let (server, mut client) = duplex(16);
+        let mut ws = WebSocket::from_raw(server, &[], Role::Server, without_heartbeat());
+        ws.feed(Message::Binary(Bytes::from(vec![0; 32])))
+            .await
+            .unwrap();
+
+        // an unmasked frame, the close frame can only be written once the client reads
+        client
+            .write_all(&Frame::text("a").to_bytes())
+            .await
+            .unwrap();
+        let reading = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(20)).await;
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let err = ws.next().await.unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ProtocolError::UnmaskedFrame));
+        assert!(ws.next().await.is_none());
+
+        let received = reading.await.unwrap();
+        assert_eq!(received[received.len() - 4..], [0x88, 0x02, 0x03, 0xea]);
+    }
+
+    #[tokio::test]
+    async fn wait_for_close_answer() {
+        let (mut ws, mut client) = server(without_heartbeat());
+        ws.close().await.unwrap();
+        assert_eq!(read_exact(&mut client, 4).await, [0x88, 0x02, 0x03, 0xe8]);
+
+        // sending is no longer possible, but the answer is still read
+        assert!(ws.send(Message::Text("late".to_string())).await.is_err());
+        send_frame(&mut client, &mut Frame::close(Some(close_code::NORMAL), "")).await;
+        assert!(matches!(ws.next().await, Some(Ok(Message::Close(_)))));
+        assert!(ws.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn wake_writer_after_reader_flushed() {
+        let (server, mut client) = duplex(64);
+        let config = WebSocketConfig {
+            ping_interval: None,
+            ..WebSocketConfig::default()
+        };
+        let (mut reader, mut writer) =
+            WebSocket::from_raw(server, &[], Role::Server, config).split();
+
+        // the writer parks on the full pipe
+        let send = tokio::spawn(async move {
+            writer
+                .send(Message::Binary(Bytes::from(vec![0; 4096])))
+                .await
+        });
+        time::sleep(Duration::from_millis(10)).await;
+
+        // the reader answers a ping and tries to flush the same pipe
+        let ping = Frame::ping(Bytes::new()).mask([1, 2, 3, 4]).to_bytes();
+        client.write_all(&ping).await.unwrap();
+        assert!(matches!(reader.next().await, Some(Ok(Message::Ping(_)))));
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while client.read(&mut buf).await.unwrap() > 0 {}
+        });
+
+        time::timeout(Duration::from_secs(1), send)
+            .await
+            .expect("writer to be woken")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,457 @@
+#![feature(async_closure)]
+
+// Conformance tests modeled after the autobahn testsuite (https://github.com/crossbario/autobahn-testsuite).
+// A scripted client talks to a real server over loopback and checks the exact bytes it answers with.
+
+use futures::{future, StreamExt, TryStreamExt};
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Once,
+    },
+    thread,
+    time::Duration,
+};
+use webserver_from_scratch::{
+    middleware,
+    router::Router,
+    websocket::{self, Message, WebSocketConfig},
+    HTTPServer,
+};
+
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+const LIMITED_MESSAGE_SIZE: usize = 1024;
+
+static START: Once = Once::new();
+static PORT: AtomicU16 = AtomicU16::new(0);
+
+fn config(max_message_size: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        ping_interval: None,
+        max_message_size: Some(max_message_size),
+        ..Default::default()
+    }
+}
+
+// starts a server which echoes all text and binary messages, once for all tests
+fn server_port() -> u16 {
+    START.call_once(|| {
+        let port = TcpListener::bind((Ipv6Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("finding a free port")
+            .port();
+        PORT.store(port, Ordering::SeqCst);
+
+        let mut server = HTTPServer::new();
+        server.get(
+            "/echo",
+            middleware!(|ctx| {
+                let websocket = websocket::upgrade_websocket(&mut ctx, config(16 << 20)).await?;
+                tokio::spawn(echo(websocket));
+            }),
+        );
+        server.get(
+            "/limited",
+            middleware!(|ctx| {
+                let websocket =
+                    websocket::upgrade_websocket(&mut ctx, config(LIMITED_MESSAGE_SIZE)).await?;
+                tokio::spawn(echo(websocket));
+            }),
+        );
+
+        thread::spawn(move || {
+            let address = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+            server.listen_blocking(address).expect("starting server");
+        });
+
+        // wait until the server accepts connections
+        for _ in 0..100 {
+            if TcpStream::connect((Ipv6Addr::LOCALHOST, port)).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("server didn't start");
+    });
+
+    PORT.load(Ordering::SeqCst)
+}
+
+async fn echo(websocket: websocket::WebSocket) {
+    let (reader, writer) = websocket.split();
+    let messages = reader.try_filter(|message| {
+        future::ready(matches!(message, Message::Text(_) | Message::Binary(_)))
+    });
+    let _ = messages.forward(writer).await;
+}
+
+/// The client side of a websocket connection, which sends and expects raw bytes
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn connect(path: &str) -> Client {
+        let mut stream = TcpStream::connect((Ipv6Addr::LOCALHOST, server_port())).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        // the server reads the request with a single read, so it's sent at once
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+
+        // read byte by byte, so no frames are consumed
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 "), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        Client { stream }
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        self.stream.write_all(frame).unwrap();
+    }
+
+    fn expect(&mut self, expected: &[u8]) {
+        let mut received = vec![0; expected.len()];
+        self.stream.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    fn expect_frame(&mut self, opcode: u8, payload: &[u8]) {
+        self.expect(&frame(opcode, payload, true, 0, None));
+    }
+
+    // the server has to send a close frame with the given code and then close the connection
+    fn expect_close(&mut self, code: u16) {
+        self.expect_frame(0x8, &code.to_be_bytes());
+        self.expect_eof();
+    }
+
+    fn expect_eof(&mut self) {
+        let mut buf = [0; 16];
+        match self.stream.read(&mut buf) {
+            Ok(0) => {}
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            result => panic!("expected the connection to be closed, got {:?}", result),
+        }
+    }
+}
+
+fn frame(opcode: u8, payload: &[u8], fin: bool, rsv: u8, mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = vec![(fin as u8) << 7 | rsv << 4 | opcode];
+    let masked = (mask.is_some() as u8) << 7;
+
+    match payload.len() {
+        len @ 0..=125 => frame.push(masked | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(masked | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(masked | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+
+    frame
+}
+
+// a masked frame, like every client has to send
+fn client_frame(opcode: u8, payload: &[u8], fin: bool) -> Vec<u8> {
+    frame(opcode, payload, fin, 0, Some(MASK))
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+// 1.* framing
+
+#[test]
+fn echo_text_payload_lengths() {
+    let mut client = Client::connect("/echo");
+    for length in [0, 125, 126, 127, 128, 65535, 65536] {
+        let payload = "*".repeat(length);
+        client.send(&client_frame(0x1, payload.as_bytes(), true));
+        client.expect_frame(0x1, payload.as_bytes());
+    }
+}
+
+#[test]
+fn echo_binary_payload_lengths() {
+    let mut client = Client::connect("/echo");
+    for length in [0, 125, 126, 65535, 65536] {
+        let payload = vec![0xfe; length];
+        client.send(&client_frame(0x2, &payload, true));
+        client.expect_frame(0x2, &payload);
+    }
+}
+
+#[test]
+fn echo_frames_sent_in_chunks() {
+    let mut client = Client::connect("/echo");
+    let payload = b"Hello World".repeat(100);
+
+    for chunk in client_frame(0x1, &payload, true).chunks(7) {
+        client.send(chunk);
+        client.stream.flush().unwrap();
+    }
+    client.expect_frame(0x1, &payload);
+}
+
+// 2.* pings and pongs
+
+#[test]
+fn answer_pings_with_identical_payload() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x9, b"", true));
+    client.expect_frame(0xa, b"");
+
+    let payload = [0xfe; 125];
+    client.send(&client_frame(0x9, &payload, true));
+    client.expect_frame(0xa, &payload);
+}
+
+#[test]
+fn ignore_unsolicited_pongs() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0xa, b"unsolicited", true));
+    client.send(&client_frame(0x1, b"after pong", true));
+    client.expect_frame(0x1, b"after pong");
+}
+
+#[test]
+fn reject_ping_larger_than_125_bytes() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x9, &[0; 126], true));
+    client.expect_close(1002);
+}
+
+// 3.* reserved bits
+
+#[test]
+fn reject_reserved_bits() {
+    for rsv in [1, 2, 3, 4, 5, 6, 7] {
+        let mut client = Client::connect("/echo");
+        client.send(&frame(0x1, b"hi", true, rsv, Some(MASK)));
+        client.expect_close(1002);
+    }
+}
+
+// 4.* opcodes
+
+#[test]
+fn reject_reserved_opcodes() {
+    for opcode in [0x3, 0x4, 0x5, 0x6, 0x7, 0xb, 0xc, 0xd, 0xe, 0xf] {
+        let mut client = Client::connect("/echo");
+        client.send(&client_frame(0x1, b"before", true));
+        client.expect_frame(0x1, b"before");
+
+        client.send(&client_frame(opcode, b"", true));
+        client.expect_close(1002);
+    }
+}
+
+// 5.* fragmentation
+
+#[test]
+fn reassemble_fragmented_messages() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x1, b"frag", false));
+    client.send(&client_frame(0x0, b"men", false));
+    client.send(&client_frame(0x0, b"ted", true));
+    client.expect_frame(0x1, b"fragmented");
+}
+
+#[test]
+fn answer_pings_between_fragments() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x2, b"one", false));
+    client.send(&client_frame(0x9, b"ping", true));
+    client.send(&client_frame(0x0, b"two", true));
+
+    client.expect_frame(0xa, b"ping");
+    client.expect_frame(0x2, b"onetwo");
+}
+
+#[test]
+fn reject_fragmented_control_frames() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x9, b"ping", false));
+    client.expect_close(1002);
+}
+
+#[test]
+fn reject_continuation_without_message() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x0, b"orphan", true));
+    client.expect_close(1002);
+}
+
+#[test]
+fn reject_new_message_during_fragmented_message() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x1, b"first", false));
+    client.send(&client_frame(0x1, b"second", true));
+    client.expect_close(1002);
+}
+
+// 6.* utf8 handling
+
+#[test]
+fn echo_valid_utf8() {
+    let mut client = Client::connect("/echo");
+    let text = "κόσμε € 𝄞";
+    client.send(&client_frame(0x1, text.as_bytes(), true));
+    client.expect_frame(0x1, text.as_bytes());
+
+    // characters split across fragments are valid
+    let (first, second) = text.as_bytes().split_at(3);
+    client.send(&client_frame(0x1, first, false));
+    client.send(&client_frame(0x0, second, true));
+    client.expect_frame(0x1, text.as_bytes());
+}
+
+#[test]
+fn reject_invalid_utf8() {
+    for payload in [
+        &b"\xff"[..],
+        b"\xc0\xaf",
+        b"\xed\xa0\x80",
+        b"\xce\xba\xe1\xbd",
+    ] {
+        let mut client = Client::connect("/echo");
+        client.send(&client_frame(0x1, payload, true));
+        client.expect_close(1007);
+    }
+}
+
+#[test]
+fn reject_invalid_utf8_before_message_is_complete() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x1, b"valid", false));
+    client.send(&client_frame(0x0, b"\xf4\x90\x80\x80", false));
+    client.expect_close(1007);
+}
+
+// 7.* close handling
+
+#[test]
+fn echo_close_code() {
+    for code in [1000, 1001, 1003, 1011, 3000, 4999] {
+        let mut client = Client::connect("/echo");
+        client.send(&client_frame(0x8, &close_payload(code, "bye"), true));
+        client.expect_close(code);
+    }
+}
+
+#[test]
+fn answer_empty_close() {
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x8, b"", true));
+    client.expect_close(1000);
+}
+
+#[test]
+fn ignore_messages_after_close() {
+    let mut client = Client::connect("/echo");
+
+    // sent at once, the server might close the connection before we could write more
+    let mut frames = client_frame(0x8, &close_payload(1000, ""), true);
+    frames.extend(client_frame(0x1, b"too late", true));
+    frames.extend(client_frame(0x9, b"too late", true));
+    client.send(&frames);
+    client.expect_close(1000);
+}
+
+#[test]
+fn reject_invalid_close_frames() {
+    // payload of a single byte
+    let mut client = Client::connect("/echo");
+    client.send(&client_frame(0x8, &[0x03], true));
+    client.expect_close(1002);
+
+    // reason which isn't valid utf8
+    let mut client = Client::connect("/echo");
+    let mut payload = close_payload(1000, "");
+    payload.extend_from_slice(b"\xff");
+    client.send(&client_frame(0x8, &payload, true));
+    client.expect_close(1007);
+}
+
+#[test]
+fn reject_invalid_close_codes() {
+    for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, 65535] {
+        let mut client = Client::connect("/echo");
+        client.send(&client_frame(0x8, &close_payload(code, ""), true));
+        client.expect_close(1002);
+    }
+}
+
+// 9.* limits
+
+#[test]
+fn reject_messages_over_size_limit() {
+    let mut client = Client::connect("/limited");
+    let payload = vec![b'a'; LIMITED_MESSAGE_SIZE];
+    client.send(&client_frame(0x1, &payload, true));
+    client.expect_frame(0x1, &payload);
+
+    client.send(&client_frame(0x1, &[b'a'; LIMITED_MESSAGE_SIZE + 1], true));
+    client.expect_close(1009);
+}
+
+#[test]
+fn reject_fragmented_messages_over_size_limit() {
+    let mut client = Client::connect("/limited");
+    client.send(&client_frame(0x2, &[0; LIMITED_MESSAGE_SIZE / 4], false));
+    for _ in 0..3 {
+        client.send(&client_frame(0x0, &[0; LIMITED_MESSAGE_SIZE / 4], false));
+    }
+    client.send(&client_frame(0x0, &[0], true));
+    client.expect_close(1009);
+}
+
+#[test]
+fn reject_huge_length_before_payload() {
+    let mut client = Client::connect("/limited");
+    let mut header = vec![0x82, 0x80 | 127];
+    header.extend_from_slice(&(1u64 << 40).to_be_bytes());
+    header.extend_from_slice(&MASK);
+    client.send(&header);
+    client.expect_close(1009);
+}
+
+// client frames have to be masked, see https://datatracker.ietf.org/doc/html/rfc6455#section-5.1
+
+#[test]
+fn reject_unmasked_frames() {
+    let mut client = Client::connect("/echo");
+    client.send(&frame(0x1, b"unmasked", true, 0, None));
+    client.expect_close(1002);
+}